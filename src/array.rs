@@ -0,0 +1,235 @@
+// The Array prototype: natives shared by every array value.
+use std::cmp;
+
+use heap::Gc;
+use object::{Object, Native, PropertyMap};
+use value::Value;
+use vm::VM;
+use error::RuntimeError;
+
+/// Fills in the prototype object every array looks its methods up on.
+pub fn install(vm: &mut VM, prototype: Gc) {
+    let mut props = PropertyMap::new();
+    add_native!("push", array_push, vm, props);
+    add_native!("pop", array_pop, vm, props);
+    add_native!("slice", array_slice, vm, props);
+    add_native!("map", array_map, vm, props);
+    add_native!("filter", array_filter, vm, props);
+    add_native!("reduce", array_reduce, vm, props);
+    add_native!("forEach", array_for_each, vm, props);
+    add_native!("indexOf", array_index_of, vm, props);
+    add_native!("join", array_join, vm, props);
+    add_native!("sort", array_sort, vm, props);
+    *vm.heap_mut().get_mut(prototype) = Object::Ordinary(props);
+}
+
+/// Runs `f` on the elements of `this`, which must be an array.
+fn with_elements<R, F>(vm: &mut VM, this: &Value, f: F) -> Result<R, RuntimeError>
+        where F: FnOnce(&mut Vec<Value>) -> R {
+    if let Value::Object(handle) = *this {
+        if let Object::Array(ref mut elements) = *vm.heap_mut().get_mut(handle) {
+            return Ok(f(elements));
+        }
+    }
+    let type_name = vm.type_of(this);
+    Err(RuntimeError::TypeError{
+        message: format!("{} is not an array", type_name),
+        operands: vec!(type_name),
+    })
+}
+
+fn elements(vm: &mut VM, this: &Value) -> Result<Vec<Value>, RuntimeError> {
+    with_elements(vm, this, |elements| elements.clone())
+}
+
+fn length(vm: &mut VM, this: &Value) -> Result<usize, RuntimeError> {
+    with_elements(vm, this, |elements| elements.len())
+}
+
+fn element(vm: &mut VM, this: &Value, i: usize) -> Result<Value, RuntimeError> {
+    with_elements(vm, this, |elements| elements.get(i).cloned().unwrap_or(Value::Undefined))
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+/// Resolves a possibly negative index argument against an array length.
+fn relative_index(arg: Option<&Value>, len: usize, default: usize) -> usize {
+    match arg {
+        Some(&Value::Number(n)) if !n.is_nan() => {
+            let n = n.trunc();
+            if n < 0.0 {
+                (len as f64 + n).max(0.0) as usize
+            } else {
+                n.min(len as f64) as usize
+            }
+        },
+        _ => default,
+    }
+}
+
+fn array_push(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    with_elements(vm, &this, |elements| {
+        elements.extend(args);
+        Value::Number(elements.len() as f64)
+    })
+}
+
+fn array_pop(vm: &mut VM, this: Value, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    with_elements(vm, &this, |elements| elements.pop().unwrap_or(Value::Undefined))
+}
+
+fn array_slice(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let elements = elements(vm, &this)?;
+    let start = relative_index(args.first(), elements.len(), 0);
+    let end = relative_index(args.get(1), elements.len(), elements.len());
+    let slice = elements[start..cmp::max(start, end)].to_vec();
+    Ok(vm.alloc(Object::Array(slice)))
+}
+
+fn array_map(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let callback = arg(&args, 0);
+    let len = length(vm, &this)?;
+    let result = vm.alloc(Object::Array(Vec::with_capacity(len)));
+    vm.push(result.clone());
+    for i in 0..len {
+        let v = element(vm, &this, i)?;
+        let args = vec!(v, Value::Number(i as f64), this.clone());
+        let mapped = vm.call_function(callback.clone(), Value::Undefined, args)?;
+        with_elements(vm, &result, |elements| elements.push(mapped))?;
+    }
+    vm.pop();
+    Ok(result)
+}
+
+fn array_filter(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let callback = arg(&args, 0);
+    let len = length(vm, &this)?;
+    let result = vm.alloc(Object::Array(Vec::new()));
+    vm.push(result.clone());
+    for i in 0..len {
+        let v = element(vm, &this, i)?;
+        // The callback can drop every other reference to the element
+        // before it is kept, so keep it rooted.
+        vm.push(v.clone());
+        let args = vec!(v.clone(), Value::Number(i as f64), this.clone());
+        let keep = vm.call_function(callback.clone(), Value::Undefined, args);
+        vm.pop();
+        if keep?.to_boolean() {
+            with_elements(vm, &result, |elements| elements.push(v))?;
+        }
+    }
+    vm.pop();
+    Ok(result)
+}
+
+fn array_reduce(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let callback = arg(&args, 0);
+    let len = length(vm, &this)?;
+    let mut start = 0;
+    let mut acc = match args.get(1) {
+        Some(init) => init.clone(),
+        None if len > 0 => {
+            start = 1;
+            element(vm, &this, 0)?
+        },
+        None => return Err(RuntimeError::type_error("reduce of empty array with no initial value")),
+    };
+    for i in start..len {
+        let v = element(vm, &this, i)?;
+        // The accumulator is only reachable from here, so keep it rooted.
+        vm.push(acc.clone());
+        let args = vec!(acc, v, Value::Number(i as f64), this.clone());
+        let result = vm.call_function(callback.clone(), Value::Undefined, args);
+        vm.pop();
+        acc = result?;
+    }
+    Ok(acc)
+}
+
+fn array_for_each(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let callback = arg(&args, 0);
+    let len = length(vm, &this)?;
+    for i in 0..len {
+        let v = element(vm, &this, i)?;
+        let args = vec!(v, Value::Number(i as f64), this.clone());
+        vm.call_function(callback.clone(), Value::Undefined, args)?;
+    }
+    Ok(Value::Undefined)
+}
+
+fn array_index_of(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let target = arg(&args, 0);
+    with_elements(vm, &this, |elements| {
+        match elements.iter().position(|e| *e == target) {
+            Some(i) => Value::Number(i as f64),
+            None => Value::Number(-1.0),
+        }
+    })
+}
+
+fn array_join(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let separator = match args.first() {
+        Some(&Value::Undefined) | None => ",".to_string(),
+        Some(s) => vm.to_string(s),
+    };
+    let parts: Vec<String> = elements(vm, &this)?.iter().map(|e| match *e {
+        Value::Undefined => String::new(),
+        ref e => vm.to_string(e),
+    }).collect();
+    Ok(Value::Str(parts.join(&separator)))
+}
+
+/// A stable merge sort that tolerates comparators which fail or which are
+/// not consistent.
+fn merge_sort<F>(mut v: Vec<Value>, less_eq: &mut F) -> Result<Vec<Value>, RuntimeError>
+        where F: FnMut(&Value, &Value) -> Result<bool, RuntimeError> {
+    if v.len() < 2 {
+        return Ok(v);
+    }
+    let right = v.split_off(v.len() / 2);
+    let left = merge_sort(v, less_eq)?;
+    let right = merge_sort(right, less_eq)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let take_left = match (left.peek(), right.peek()) {
+            (Some(a), Some(b)) => less_eq(a, b)?,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        merged.push(if take_left { left.next() } else { right.next() }.unwrap());
+    }
+    Ok(merged)
+}
+
+fn array_sort(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let elements = elements(vm, &this)?;
+    // Like JS, undefined always sorts to the end and is never compared.
+    let (defined, undefined): (Vec<Value>, Vec<Value>) =
+        elements.into_iter().partition(|e| *e != Value::Undefined);
+    // The comparator may empty the array, leaving the values being sorted
+    // reachable only from here, so keep them rooted in an array of their own.
+    let rooted = vm.alloc(Object::Array(defined.clone()));
+    vm.push(rooted);
+    let sorted = match arg(&args, 0) {
+        Value::Undefined => {
+            merge_sort(defined, &mut |a, b| Ok(vm.to_string(a) <= vm.to_string(b)))
+        },
+        comparator => merge_sort(defined, &mut |a, b| {
+            let args = vec!(a.clone(), b.clone());
+            match vm.call_function(comparator.clone(), Value::Undefined, args)? {
+                Value::Number(n) => Ok(n <= 0.0 || n.is_nan()),
+                _ => Ok(true),
+            }
+        }),
+    };
+    vm.pop();
+    let mut sorted = sorted?;
+    sorted.extend(undefined);
+    with_elements(vm, &this, |elements| *elements = sorted)?;
+    Ok(this)
+}
@@ -1,22 +1,90 @@
-use lexer::BinOp;
+use lexer::{BinOp, UnaryOp, Span};
 use value::Value;
+use object::FunctionKind;
 
+/// An expression and the source it was parsed from.
 #[derive(Debug, Clone)]
-pub enum Expression {
+pub struct Expression {
+    pub kind:   ExpressionKind,
+    pub span:   Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression{kind, span}
+    }
+}
+
+/// The `pos` of an expression is the span of the operator or keyword that
+/// errors in it are reported at.
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
     Atom(Value),
-    BinaryOperation{l_expr: Box<Expression>, op: BinOp, r_expr: Box<Expression>},
+    BinaryOperation{l_expr: Box<Expression>, op: BinOp, r_expr: Box<Expression>, pos: Span},
+    UnaryOperation{op: UnaryOp, expr: Box<Expression>},
     GetName(String),
-    Function{name: Option<String>, args: Vec<String>, body: Vec<Statement>},
-    Call(Vec<Expression>),
+    Function{name: Option<String>, args: Vec<String>, body: Vec<Statement>, kind: FunctionKind},
+    Call(Vec<Expression>, Span),
+    Object(Vec<(String, Expression)>),
+    Array(Vec<Expression>),
+    Member{object: Box<Expression>, property: Box<Expression>, pos: Span},
     Return(Box<Expression>),
+    /// Stores into a `GetName` or `Member` target, evaluating to the value.
+    Assign{target: Box<Expression>, value: Box<Expression>, pos: Span},
+    Conditional{cond: Box<Expression>, then: Box<Expression>, otherwise: Box<Expression>},
+    /// A template literal: the text pieces, with the value of each
+    /// substitution going between two of them.
+    Template{pieces: Vec<String>, substitutions: Vec<Expression>},
+    /// Expressions joined by the comma operator.
+    Sequence(Vec<Expression>),
+    /// Suspends the generator function it is in; evaluates to the value the
+    /// generator is resumed with.
+    Yield{value: Option<Box<Expression>>, pos: Span},
+    /// Suspends the async function it is in until the value settles.
+    Await{value: Box<Expression>, pos: Span},
+}
+
+/// A statement and the source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub kind:   StatementKind,
+    pub span:   Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Statement {
+        Statement{kind, span}
+    }
 }
 
 #[derive(Debug, Clone)]
-pub enum Statement {
+pub enum StatementKind {
     Expr(Expression),
-    Assignment{name: String, expr: Expression},
+    Let{name: String, expr: Expression},
     If{cond: Expression, body: Vec<Statement>},
     IfElse{cond: Expression, body: Vec<Statement>, else_body: Vec<Statement>},
-    While{cond: Expression, body: Vec<Statement>}
+    While{cond: Expression, body: Vec<Statement>},
+    DoWhile{body: Vec<Statement>, cond: Expression},
+    For{
+        init:   Option<Box<Statement>>,
+        cond:   Option<Expression>,
+        update: Option<Expression>,
+        body:   Vec<Statement>,
+    },
+    /// `for (let name of iterable)`, or without `let` to assign an
+    /// existing variable.
+    ForOf{name: String, declare: bool, iterable: Expression, body: Vec<Statement>},
+    /// `for (let name in object)` over the object's own keys.
+    ForIn{name: String, declare: bool, object: Expression, body: Vec<Statement>},
+    Break(Option<String>),
+    Continue(Option<String>),
+    Labeled{label: String, body: Box<Statement>},
+    Throw{expr: Expression, pos: Span},
+    Try{
+        body:       Vec<Statement>,
+        catch_name: Option<String>,
+        catch_body: Option<Vec<Statement>>,
+        finally:    Option<Vec<Statement>>,
+    },
 }
 
@@ -1,97 +1,972 @@
-use lexer::BinOp;
-use ast::{Statement, Expression};
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use lexer::{BinOp, UnaryOp, Span};
+use ast::{Statement, StatementKind, Expression, ExpressionKind};
 use opcode::OpCode;
 use value::Value;
-use object::Object;
-
-fn compile_expression(script: &mut Vec<OpCode>, expr: &Expression) {
-    match *expr {
-        Expression::Atom(ref v) => script.push(OpCode::Val(v.clone())),
-        Expression::BinaryOperation{ref l_expr, ref op, ref r_expr} => {
-            compile_expression(script, r_expr);
-            compile_expression(script, l_expr);
-            match *op {
-                BinOp::Plus     => script.push(OpCode::Add),
-                BinOp::Min      => script.push(OpCode::Sub),
-                BinOp::Mul      => script.push(OpCode::Mul),
-                BinOp::Div      => script.push(OpCode::Div),
-                BinOp::EqEq     => script.push(OpCode::EqEq),
-                BinOp::NotEq    => script.push(OpCode::NotEq),
-                BinOp::Lt       => script.push(OpCode::Lt),
-                BinOp::LtEq     => script.push(OpCode::LtEq),
-                BinOp::Gt       => script.push(OpCode::Gt),
-                BinOp::GtEq     => script.push(OpCode::GtEq),
+use object::{Function, FunctionKind, Capture, Handler};
+use error::Location;
+use diagnostic::Diagnostic;
+
+/// A variable living in a stack slot of the function being compiled.
+struct Local {
+    name:       String,
+    depth:      usize,
+    captured:   bool,
+}
+
+/// What has to run when code leaves a region early.
+#[derive(Clone)]
+enum Finally {
+    Block(Vec<Statement>),
+    /// Tells the iterator in the given local that the loop is done with it.
+    CloseIterator(usize),
+}
+
+/// Code covered by a `try` block or a `catch` block that has a handler, or
+/// by the body of a `for...of` loop. Inlined `finally` code is left out of
+/// it, so a region can end up as several ranges.
+struct Region {
+    start:      Option<usize>,  // where the range being emitted began
+    ranges:     Vec<(usize, usize)>,
+    depth:      usize,          // how many locals were live when it began
+    finally:    Option<Finally>, // to run when leaving early
+}
+
+/// A statement that `break` or `continue` can jump out of. Jumps whose
+/// destination isn't emitted yet are patched once it is.
+struct JumpTarget {
+    labels:     Vec<String>,
+    is_loop:    bool,
+    depth:      usize,          // the scope depth inside the statement
+    regions:    usize,          // how many regions were open outside it
+    continue_at: Option<usize>, // where `continue` goes, once known
+    breaks:     Vec<usize>,
+    continues:  Vec<usize>,
+}
+
+/// Compiler state for one function body; these nest as function expressions
+/// nest, the script itself being the outermost.
+struct FunctionState {
+    code:       Vec<OpCode>,
+    lines:      Vec<Span>,
+    locals:     Vec<Local>,
+    captures:   Vec<Capture>,
+    depth:      usize,
+    handlers:   Vec<Handler>,
+    regions:    Vec<Region>,
+    targets:    Vec<JumpTarget>,
+    labels:     Vec<String>,    // labels for the statement about to start
+}
+
+impl FunctionState {
+    fn new(depth: usize) -> FunctionState {
+        FunctionState{
+            code: Vec::new(),
+            lines: Vec::new(),
+            locals: Vec::new(),
+            captures: Vec::new(),
+            depth,
+            handlers: Vec::new(),
+            regions: Vec::new(),
+            targets: Vec::new(),
+            labels: Vec::new(),
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|l| l.name == name)
+    }
+
+    fn add_capture(&mut self, capture: Capture) -> usize {
+        if let Some(i) = self.captures.iter().position(|c| *c == capture) {
+            return i;
+        }
+        self.captures.push(capture);
+        self.captures.len() - 1
+    }
+}
+
+/// Where a name lives, as far as the compiler can tell.
+enum Binding {
+    Local(usize),
+    Upvalue(usize),
+    Global,
+}
+
+/// A global read by the script that nothing in the script declares or
+/// assigns, and that the host did not say it provides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub name:       String,
+    pub location:   Location,
+    pub span:       Span,
+}
+
+impl Warning {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::warning(&format!("'{}' is never declared", self.name), self.span.clone())
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: warning: '{}' is never declared", self.location, self.name)
+    }
+}
+
+struct Compiler {
+    functions:  Vec<FunctionState>,
+    file:       Rc<String>,
+    // The source attributed to instructions as they are emitted.
+    span:       Span,
+    // Every name declared or assigned anywhere in the script, and every
+    // global read, for `Warning`s.
+    declared:   HashSet<String>,
+    global_reads: Vec<(String, Span)>,
+}
+
+impl Compiler {
+    fn state(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: OpCode) -> usize {
+        let span = self.span.clone();
+        let state = self.state();
+        state.code.push(op);
+        state.lines.push(span);
+        state.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch_jump(&mut self, at: usize) {
+        let offset = (self.state().code.len() - at) as i32;
+        match self.state().code[at] {
+            OpCode::JumpIfNot(ref mut n) |
+            OpCode::JumpIf(ref mut n) |
+            OpCode::JumpIfDefined(ref mut n) |
+            OpCode::IterNext(ref mut n) |
+            OpCode::Yield(ref mut n, _) |
+            OpCode::Jump(ref mut n) => *n = offset,
+            _ => panic!("patching a non-jump instruction"),
+        }
+    }
+
+    fn emit_loop(&mut self, start: usize) {
+        let offset = start as i32 - self.state().code.len() as i32;
+        self.emit(OpCode::Jump(offset));
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state().depth -= 1;
+        loop {
+            let captured = {
+                let state = self.state();
+                match state.locals.last() {
+                    Some(l) if l.depth > state.depth => l.captured,
+                    _ => break,
+                }
+            };
+            self.state().locals.pop();
+            self.emit(if captured { OpCode::CloseUpvalue } else { OpCode::Pop });
+        }
+    }
+
+    /// Ends a scope whose code never falls through to what follows it, so
+    /// there is nothing to pop.
+    fn abandon_scope(&mut self) {
+        let state = self.state();
+        state.depth -= 1;
+        while state.locals.last().is_some_and(|l| l.depth > state.depth) {
+            state.locals.pop();
+        }
+    }
+
+    fn begin_region(&mut self, finally: Option<Finally>) {
+        let state = self.state();
+        state.regions.push(Region{
+            start: Some(state.code.len()),
+            ranges: Vec::new(),
+            depth: state.locals.len(),
+            finally,
+        });
+    }
+
+    /// Stops covering code with the regions from `from` inwards.
+    fn suspend_regions(&mut self, from: usize) {
+        let state = self.state();
+        let end = state.code.len();
+        for region in &mut state.regions[from..] {
+            if let Some(start) = region.start.take() {
+                region.ranges.push((start, end));
+            }
+        }
+    }
+
+    fn resume_regions(&mut self, from: usize) {
+        let state = self.state();
+        let start = state.code.len();
+        for region in &mut state.regions[from..] {
+            if region.start.is_none() {
+                region.start = Some(start);
+            }
+        }
+    }
+
+    /// Emits the `finally` blocks of the regions from `from` inwards, for
+    /// leaving them early. Regions stay suspended until `resume_regions`.
+    fn compile_finally_blocks(&mut self, from: usize) {
+        for i in (from..self.state().regions.len()).rev() {
+            // A finally block that leaves early itself mustn't run again.
+            if let Some(finally) = self.state().regions[i].finally.take() {
+                // An error in the finally code belongs to the enclosing try.
+                self.suspend_regions(i);
+                match finally {
+                    Finally::Block(ref block) => self.compile_block(block),
+                    Finally::CloseIterator(slot) => {
+                        self.emit(OpCode::GetLocal(slot));
+                        self.emit(OpCode::IterClose);
+                    },
+                }
+                self.state().regions[i].finally = Some(finally);
             }
-        },
-        Expression::GetName(ref n) => {
-            script.push(OpCode::GetName(n.clone()))
-        },
-        Expression::Function{ref name, ref args, ref body} => {
-            let s = compile_script(body.clone());
-            let o = Object::Function{args: args.clone(), body: s};
-            script.push(OpCode::Val(Value::Object(o)));
-            match *name {
-                Some(ref v) => {
-                    script.push(OpCode::Val(Value::Str(v.clone())));
-                    script.push(OpCode::Def);
-                },
-                None => ()
+        }
+    }
+
+    /// Starts a statement that `break`, and `continue` if it is a loop, can
+    /// jump out of. The labels in front of it go with it.
+    fn begin_target(&mut self, is_loop: bool, continue_at: Option<usize>) {
+        let state = self.state();
+        let labels = mem::take(&mut state.labels);
+        state.targets.push(JumpTarget{
+            labels,
+            is_loop,
+            depth: state.depth,
+            regions: state.regions.len(),
+            continue_at,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+    }
+
+    /// Sends the pending `continue`s of the innermost target to the next
+    /// instruction.
+    fn patch_continues(&mut self) {
+        let continues = mem::take(&mut self.state().targets.last_mut().unwrap().continues);
+        for jump in continues {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn end_target(&mut self) {
+        let target = self.state().targets.pop().unwrap();
+        for jump in target.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// Leaves the statement a `break` or `continue` refers to, running any
+    /// finally blocks and popping any locals in between.
+    fn compile_jump(&mut self, label: &Option<String>, is_continue: bool) {
+        let index = self.state().targets.iter().rposition(|t| match *label {
+            Some(ref label) => t.labels.contains(label),
+            None => t.is_loop,
+        }).expect("the parser rejects jumps without a target");
+        let (depth, regions) = {
+            let target = &self.state().targets[index];
+            (target.depth, target.regions)
+        };
+        self.compile_finally_blocks(regions);
+        let locals: Vec<bool> = self.state().locals.iter().rev()
+            .take_while(|l| l.depth > depth)
+            .map(|l| l.captured)
+            .collect();
+        for captured in locals {
+            self.emit(if captured { OpCode::CloseUpvalue } else { OpCode::Pop });
+        }
+        match (is_continue, self.state().targets[index].continue_at) {
+            (true, Some(start)) => self.emit_loop(start),
+            (true, None) => {
+                let jump = self.emit(OpCode::Jump(0));
+                self.state().targets[index].continues.push(jump);
+            },
+            (false, _) => {
+                let jump = self.emit(OpCode::Jump(0));
+                self.state().targets[index].breaks.push(jump);
+            },
+        }
+        self.resume_regions(regions);
+    }
+
+    fn end_region(&mut self) -> Region {
+        let innermost = self.state().regions.len() - 1;
+        self.suspend_regions(innermost);
+        self.state().regions.pop().unwrap()
+    }
+
+    /// Sends errors raised inside `region` to whatever is emitted next.
+    fn handle_region(&mut self, region: Region) {
+        let state = self.state();
+        let target = state.code.len();
+        for (start, end) in region.ranges {
+            if start < end {
+                state.handlers.push(Handler{start, end, target, depth: region.depth});
             }
-        },
-        Expression::Call(ref args) => {
-            for e in args {
-                compile_expression(script, e);
+        }
+    }
+
+    /// Runs the `finally` blocks being left and returns the value on top of
+    /// the stack.
+    fn compile_return(&mut self) {
+        if self.state().regions.iter().all(|r| r.finally.is_none()) {
+            self.emit(OpCode::Ret);
+            return;
+        }
+        // Keep the result in a local so the finally code can use the stack.
+        self.begin_scope();
+        self.add_local("");
+        let result = self.state().locals.len() - 1;
+        self.compile_finally_blocks(0);
+        self.emit(OpCode::GetLocal(result));
+        self.emit(OpCode::Ret);
+        self.abandon_scope();
+        self.resume_regions(0);
+    }
+
+    /// Runs a `finally` block for an error on top of the stack, then
+    /// raises the error again.
+    fn compile_rethrow(&mut self, finally: &[Statement]) {
+        self.begin_scope();
+        self.add_local("");
+        let error = self.state().locals.len() - 1;
+        self.compile_block(finally);
+        self.emit(OpCode::GetLocal(error));
+        self.emit(OpCode::Throw);
+        self.abandon_scope();
+    }
+
+    fn compile_try(&mut self, body: &[Statement], catch_name: &Option<String>,
+                   catch_body: &Option<Vec<Statement>>, finally: &Option<Vec<Statement>>) {
+        self.begin_region(finally.clone().map(Finally::Block));
+        self.compile_block(body);
+        let region = self.end_region();
+        let mut exits = vec!(self.emit(OpCode::Jump(0)));
+        self.handle_region(region);
+        if let Some(ref catch_body) = *catch_body {
+            // The error is on top of the stack, where the catch binding's
+            // slot is.
+            self.begin_region(finally.clone().map(Finally::Block));
+            self.begin_scope();
+            self.add_local(catch_name.as_ref().map_or("", |n| &n[..]));
+            self.compile_statements(catch_body);
+            self.end_scope();
+            let region = self.end_region();
+            exits.push(self.emit(OpCode::Jump(0)));
+            if finally.is_some() {
+                self.handle_region(region);
             }
-            script.push(OpCode::Val(Value::Number((args.len() as f64) - 1.0)));
-            script.push(OpCode::Call);
-        },
-        Expression::Return(ref e) => {
-           compile_expression(script, e);
-           script.push(OpCode::Ret);
-        },
+        }
+        if let Some(ref finally) = *finally {
+            self.compile_rethrow(finally);
+        }
+        for exit in exits {
+            self.patch_jump(exit);
+        }
+        if let Some(ref finally) = *finally {
+            self.compile_block(finally);
+        }
+    }
+
+    /// Whether a `let` here defines a global rather than a local.
+    fn at_global_scope(&self) -> bool {
+        self.functions.len() == 1 && self.functions[0].depth == 0
+    }
+
+    /// Claims the value on top of the stack as the local `name`.
+    fn add_local(&mut self, name: &str) {
+        self.declared.insert(name.to_string());
+        let depth = self.state().depth;
+        self.state().locals.push(Local{name: name.to_string(), depth, captured: false});
+    }
+
+    fn resolve_upvalue(&mut self, func: usize, name: &str) -> Option<usize> {
+        if func == 0 {
+            return None;
+        }
+        let enclosing = func - 1;
+        if let Some(slot) = self.functions[enclosing].resolve_local(name) {
+            self.functions[enclosing].locals[slot].captured = true;
+            return Some(self.functions[func].add_capture(Capture{index: slot, is_local: true}));
+        }
+        match self.resolve_upvalue(enclosing, name) {
+            Some(index) => Some(self.functions[func].add_capture(Capture{index, is_local: false})),
+            None => None,
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Binding {
+        if let Some(slot) = self.state().resolve_local(name) {
+            return Binding::Local(slot);
+        }
+        let current = self.functions.len() - 1;
+        match self.resolve_upvalue(current, name) {
+            Some(index) => Binding::Upvalue(index),
+            None => Binding::Global,
+        }
+    }
+
+    fn compile_expression(&mut self, expr: &Expression) {
+        self.span = expr.span.clone();
+        match expr.kind {
+            ExpressionKind::Atom(ref v) => { self.emit(OpCode::Val(v.clone())); },
+            ExpressionKind::BinaryOperation{ref l_expr, ref op, ref r_expr, ..} if op.is_logical() => {
+                // Short-circuit: the left value is the result unless it
+                // says to go on to the right.
+                self.compile_expression(l_expr);
+                self.emit(OpCode::Dup);
+                let end = self.emit(match *op {
+                    BinOp::And  => OpCode::JumpIfNot(0),
+                    BinOp::Or   => OpCode::JumpIf(0),
+                    _           => OpCode::JumpIfDefined(0),
+                });
+                self.emit(OpCode::Pop);
+                self.compile_expression(r_expr);
+                self.patch_jump(end);
+            },
+            ExpressionKind::BinaryOperation{ref l_expr, ref op, ref r_expr, ref pos} => {
+                self.compile_expression(l_expr);
+                self.compile_expression(r_expr);
+                self.span = pos.clone();
+                self.emit(match *op {
+                    BinOp::Plus     => OpCode::Add,
+                    BinOp::Min      => OpCode::Sub,
+                    BinOp::Mul      => OpCode::Mul,
+                    BinOp::Div      => OpCode::Div,
+                    BinOp::EqEq     => OpCode::EqEq,
+                    BinOp::NotEq    => OpCode::NotEq,
+                    BinOp::Lt       => OpCode::Lt,
+                    BinOp::LtEq     => OpCode::LtEq,
+                    BinOp::Gt       => OpCode::Gt,
+                    BinOp::GtEq     => OpCode::GtEq,
+                    BinOp::And | BinOp::Or | BinOp::Nullish => unreachable!(),
+                });
+            },
+            ExpressionKind::GetName(ref n) => {
+                let op = match self.resolve(n) {
+                    Binding::Local(slot) => OpCode::GetLocal(slot),
+                    Binding::Upvalue(index) => OpCode::GetUpvalue(index),
+                    Binding::Global => {
+                        self.global_reads.push((n.clone(), expr.span.clone()));
+                        OpCode::GetName(n.clone())
+                    },
+                };
+                self.emit(op);
+            },
+            ExpressionKind::UnaryOperation{ref op, ref expr} => self.compile_unary(op, expr),
+            ExpressionKind::Function{ref name, ref args, ref body, kind} => {
+                self.compile_function(name, args, body, kind);
+            },
+            ExpressionKind::Call(ref args, ref pos) => {
+                match args[0].kind {
+                    ExpressionKind::Member{ref object, ref property, ..} => {
+                        self.compile_expression(object);
+                        self.compile_expression(property);
+                    },
+                    _ => {
+                        self.compile_expression(&args[0]);
+                        self.emit(OpCode::Val(Value::Undefined));
+                    },
+                }
+                for e in &args[1..] {
+                    self.compile_expression(e);
+                }
+                self.span = pos.clone();
+                self.emit(match args[0].kind {
+                    ExpressionKind::Member{..} => OpCode::CallMethod(args.len() - 1),
+                    _ => OpCode::Call(args.len() - 1),
+                });
+            },
+            ExpressionKind::Array(ref elements) => {
+                for e in elements {
+                    self.compile_expression(e);
+                }
+                self.emit(OpCode::NewArray(elements.len()));
+            },
+            ExpressionKind::Object(ref properties) => {
+                for (key, value) in properties {
+                    self.emit(OpCode::Val(Value::Str(key.clone())));
+                    self.compile_expression(value);
+                }
+                self.emit(OpCode::NewObject(properties.len()));
+            },
+            ExpressionKind::Member{ref object, ref property, ref pos} => {
+                self.compile_expression(object);
+                self.compile_expression(property);
+                self.span = pos.clone();
+                self.emit(OpCode::GetProp);
+            },
+            ExpressionKind::Return(ref e) => {
+                self.compile_expression(e);
+                self.compile_return();
+            },
+            ExpressionKind::Yield{ref value, ref pos} => {
+                match *value {
+                    Some(ref value) => self.compile_expression(value),
+                    None => { self.emit(OpCode::Val(Value::Undefined)); },
+                }
+                self.span = pos.clone();
+                // A generator resumed by `return()` goes on to return from
+                // here, running any finally blocks on the way, once the VM
+                // has dropped the temporaries above the locals.
+                let locals = self.state().locals.len();
+                let resumed = self.emit(OpCode::Yield(0, locals));
+                let next = self.emit(OpCode::Jump(0));
+                self.patch_jump(resumed);
+                self.compile_return();
+                self.patch_jump(next);
+            },
+            ExpressionKind::Template{ref pieces, ref substitutions} => {
+                // Concatenate the pieces with the substitutions as strings,
+                // leaving out empty pieces after the first.
+                self.emit(OpCode::Val(Value::Str(pieces[0].clone())));
+                for (substitution, piece) in substitutions.iter().zip(&pieces[1..]) {
+                    self.compile_expression(substitution);
+                    self.emit(OpCode::ToStr);
+                    self.emit(OpCode::Add);
+                    if !piece.is_empty() {
+                        self.emit(OpCode::Val(Value::Str(piece.clone())));
+                        self.emit(OpCode::Add);
+                    }
+                }
+            },
+            ExpressionKind::Await{ref value, ref pos} => {
+                self.compile_expression(value);
+                self.span = pos.clone();
+                // Async functions are never resumed by `return()`.
+                let locals = self.state().locals.len();
+                self.emit(OpCode::Yield(1, locals));
+            },
+            ExpressionKind::Assign{ref target, ref value, ref pos} => {
+                self.compile_assignment(target, value, pos, true);
+            },
+            ExpressionKind::Conditional{ref cond, ref then, ref otherwise} => {
+                self.compile_expression(cond);
+                let else_jump = self.emit(OpCode::JumpIfNot(0));
+                self.compile_expression(then);
+                let end_jump = self.emit(OpCode::Jump(0));
+                self.patch_jump(else_jump);
+                self.compile_expression(otherwise);
+                self.patch_jump(end_jump);
+            },
+            ExpressionKind::Sequence(ref exprs) => {
+                for (i, e) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(OpCode::Pop);
+                    }
+                    self.compile_expression(e);
+                }
+            },
+        }
+    }
+
+    fn compile_unary(&mut self, op: &UnaryOp, expr: &Expression) {
+        match (op, &expr.kind) {
+            // Negative literals are folded.
+            (&UnaryOp::Neg, &ExpressionKind::Atom(Value::Number(n))) => {
+                self.emit(OpCode::Val(Value::Number(-n)));
+                return;
+            },
+            // `typeof` of an undeclared global is "undefined" rather than a
+            // ReferenceError.
+            (&UnaryOp::TypeOf, ExpressionKind::GetName(n)) => {
+                if let Binding::Global = self.resolve(n) {
+                    self.emit(OpCode::TypeOfName(n.clone()));
+                    return;
+                }
+            },
+            _ => (),
+        }
+        self.compile_expression(expr);
+        self.emit(match *op {
+            UnaryOp::Neg    => OpCode::Neg,
+            UnaryOp::Plus   => OpCode::Plus,
+            UnaryOp::Not    => OpCode::Not,
+            UnaryOp::TypeOf => OpCode::TypeOf,
+            UnaryOp::Void   => OpCode::Void,
+        });
+    }
+
+    fn compile_function(&mut self, name: &Option<String>, args: &[String], body: &[Statement],
+                        kind: FunctionKind) {
+        let mut state = FunctionState::new(1);
+        // Slot zero holds the callee itself, which lets a named function
+        // expression refer to itself by name. Slot one holds `this`.
+        let own_name = name.clone().unwrap_or_default();
+        state.locals.push(Local{name: own_name, depth: 1, captured: false});
+        state.locals.push(Local{name: "this".to_string(), depth: 1, captured: false});
+        for arg in args {
+            self.declared.insert(arg.clone());
+            state.locals.push(Local{name: arg.clone(), depth: 1, captured: false});
+        }
+        self.functions.push(state);
+        self.compile_statements(body);
+        self.emit(OpCode::Val(Value::Undefined));
+        self.emit(OpCode::Ret);
+        let state = self.functions.pop().unwrap();
+        let function = Function{
+            name: name.clone(),
+            arity: args.len(),
+            captures: state.captures,
+            body: state.code,
+            file: self.file.clone(),
+            lines: state.lines,
+            handlers: state.handlers,
+            kind,
+        };
+        self.emit(OpCode::Closure(Rc::new(function)));
+    }
+
+    /// Compiles a loop over what the iterator made by `get_iterator` from
+    /// `source` produces.
+    fn compile_for_each(&mut self, name: &str, declare: bool, source: &Expression,
+                        get_iterator: OpCode, body: &[Statement]) {
+        // Iterators over keys have nothing to clean up when a loop stops
+        // early; others hear about it through their `return` method.
+        let closes = matches!(get_iterator, OpCode::GetIterator);
+        self.begin_scope();
+        self.compile_expression(source);
+        self.emit(get_iterator);
+        self.add_local("");
+        let iterator = self.state().locals.len() - 1;
+        // The region is outside the loop as a jump target, so `continue`
+        // leaves the iterator open. It only covers the body, since an
+        // iterator that fails isn't closed.
+        let region = self.state().regions.len();
+        if closes {
+            self.begin_region(Some(Finally::CloseIterator(iterator)));
+            self.suspend_regions(region);
+        }
+        let start = self.state().code.len();
+        self.begin_target(true, Some(start));
+        let exit = self.emit(OpCode::IterNext(0));
+        self.resume_regions(region);
+        // A declared variable is new on every iteration, so closures in the
+        // body each see their own.
+        self.begin_scope();
+        if declare {
+            self.add_local(name);
+        } else {
+            self.store_name(name);
+        }
+        self.compile_statements(body);
+        self.end_scope();
+        self.emit_loop(start);
+        self.patch_jump(exit);
+        if !closes {
+            self.end_target();
+            self.end_scope();
+            return;
+        }
+        let region = self.end_region();
+        let mut exits = vec!(self.emit(OpCode::Jump(0)));
+        // A `break` out of this loop lands here, past the code that closes
+        // iterators for jumps further out.
+        self.end_target();
+        self.emit(OpCode::GetLocal(iterator));
+        self.emit(OpCode::IterClose);
+        exits.push(self.emit(OpCode::Jump(0)));
+        // An error in the body closes the iterator and carries on, even if
+        // closing it fails too.
+        self.handle_region(region);
+        self.begin_scope();
+        self.add_local("");
+        let error = self.state().locals.len() - 1;
+        self.begin_region(None);
+        self.emit(OpCode::GetLocal(iterator));
+        self.emit(OpCode::IterClose);
+        let closing = self.end_region();
+        let rethrow = self.emit(OpCode::Jump(0));
+        self.handle_region(closing);
+        self.emit(OpCode::Pop);
+        self.patch_jump(rethrow);
+        self.emit(OpCode::GetLocal(error));
+        self.emit(OpCode::Throw);
+        self.abandon_scope();
+        for exit in exits {
+            self.patch_jump(exit);
+        }
+        self.end_scope();
+    }
+
+    /// Compiles an expression only for what it does, discarding its value.
+    fn compile_effect(&mut self, expr: &Expression) {
+        match expr.kind {
+            ExpressionKind::Assign{ref target, ref value, ref pos} => {
+                self.span = expr.span.clone();
+                self.compile_assignment(target, value, pos, false);
+            },
+            _ => {
+                self.compile_expression(expr);
+                self.emit(OpCode::Pop);
+            },
+        }
+    }
+
+    /// Pops the value on top of the stack into the variable `name`.
+    fn store_name(&mut self, name: &str) {
+        match self.resolve(name) {
+            Binding::Local(slot) => { self.emit(OpCode::SetLocal(slot)); },
+            Binding::Upvalue(index) => { self.emit(OpCode::SetUpvalue(index)); },
+            Binding::Global => {
+                self.declared.insert(name.to_string());
+                self.emit(OpCode::Val(Value::Str(name.to_string())));
+                self.emit(OpCode::Def);
+            },
+        }
+    }
+
+    /// Stores `value` into `target`, leaving the value on the stack if
+    /// `keep` is set.
+    fn compile_assignment(&mut self, target: &Expression, value: &Expression, pos: &Span,
+                          keep: bool) {
+        match target.kind {
+            ExpressionKind::GetName(ref name) => {
+                self.compile_expression(value);
+                if keep {
+                    self.emit(OpCode::Dup);
+                }
+                self.store_name(name);
+            },
+            ExpressionKind::Member{ref object, ref property, ..} => {
+                self.compile_expression(object);
+                self.compile_expression(property);
+                self.compile_expression(value);
+                self.span = pos.clone();
+                self.emit(OpCode::SetProp);
+                if !keep {
+                    self.emit(OpCode::Pop);
+                }
+            },
+            _ => panic!("the parser only produces name and member targets"),
+        }
+    }
+
+    /// Binds the value on top of the stack to a new variable in the
+    /// innermost scope.
+    fn define(&mut self, name: &str) {
+        if self.at_global_scope() {
+            self.declared.insert(name.to_string());
+            self.emit(OpCode::Val(Value::Str(name.to_string())));
+            self.emit(OpCode::Def);
+        } else {
+            self.add_local(name);
+        }
+    }
+
+    fn compile_block(&mut self, statements: &[Statement]) {
+        self.begin_scope();
+        self.compile_statements(statements);
+        self.end_scope();
+    }
+
+    fn compile_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.compile_statement(statement);
+        }
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) {
+        self.span = statement.span.clone();
+        match statement.kind {
+            StatementKind::Expr(Expression{
+                kind: ExpressionKind::Function{name: Some(ref name), ref args, ref body, kind}, ..
+            }) => {
+                // A function statement declares its name in the enclosing
+                // scope. Declaring it first lets the body capture it.
+                if self.at_global_scope() {
+                    self.compile_function(&Some(name.clone()), args, body, kind);
+                    self.define(name);
+                } else {
+                    self.add_local(name);
+                    self.compile_function(&Some(name.clone()), args, body, kind);
+                }
+            },
+            StatementKind::Expr(ref e) => self.compile_effect(e),
+            StatementKind::Let{ref name, ref expr} => {
+                self.compile_expression(expr);
+                self.define(name);
+            },
+            StatementKind::If{ref cond, ref body} => {
+                self.compile_expression(cond);
+                let jump = self.emit(OpCode::JumpIfNot(0));
+                self.compile_block(body);
+                self.patch_jump(jump);
+            },
+            StatementKind::IfElse{ref cond, ref body, ref else_body} => {
+                self.compile_expression(cond);
+                let else_jump = self.emit(OpCode::JumpIfNot(0));
+                self.compile_block(body);
+                let end_jump = self.emit(OpCode::Jump(0));
+                self.patch_jump(else_jump);
+                self.compile_block(else_body);
+                self.patch_jump(end_jump);
+            },
+            StatementKind::While{ref cond, ref body} => {
+                let start = self.state().code.len();
+                self.begin_target(true, Some(start));
+                self.compile_expression(cond);
+                let exit = self.emit(OpCode::JumpIfNot(0));
+                self.compile_block(body);
+                self.emit_loop(start);
+                self.patch_jump(exit);
+                self.end_target();
+            },
+            StatementKind::DoWhile{ref body, ref cond} => {
+                let start = self.state().code.len();
+                self.begin_target(true, None);
+                self.compile_block(body);
+                self.patch_continues();
+                self.compile_expression(cond);
+                let offset = start as i32 - self.state().code.len() as i32;
+                self.emit(OpCode::JumpIf(offset));
+                self.end_target();
+            },
+            StatementKind::For{ref init, ref cond, ref update, ref body} => {
+                // The loop variable is scoped to the loop.
+                self.begin_scope();
+                if let Some(ref init) = *init {
+                    self.compile_statement(init);
+                }
+                let declared = match init.as_ref().map(|init| &init.kind) {
+                    Some(StatementKind::Let{ref name, ..}) => Some((name, self.state().locals.len() - 1)),
+                    _ => None,
+                };
+                let start = self.state().code.len();
+                // A declared variable is copied into a new slot for every
+                // iteration, so closures in the body each see their own. The
+                // update works on the original once the copy is closed.
+                self.begin_scope();
+                if let Some((name, slot)) = declared {
+                    self.emit(OpCode::GetLocal(slot));
+                    self.add_local(name);
+                }
+                self.begin_target(true, None);
+                let exit = cond.as_ref().map(|cond| {
+                    self.compile_expression(cond);
+                    self.emit(OpCode::JumpIfNot(0))
+                });
+                self.compile_block(body);
+                self.patch_continues();
+                let copy = declared.map(|(_, slot)| {
+                    let copy = self.state().locals.len() - 1;
+                    self.emit(OpCode::GetLocal(copy));
+                    self.emit(OpCode::SetLocal(slot));
+                    self.state().locals[copy].captured
+                });
+                self.end_scope();
+                if let Some(ref update) = *update {
+                    self.compile_effect(update);
+                }
+                self.emit_loop(start);
+                if let Some(exit) = exit {
+                    self.patch_jump(exit);
+                }
+                // Leaving the loop skips the end of the iteration, copy and
+                // all.
+                self.end_target();
+                if let Some(captured) = copy {
+                    self.emit(if captured { OpCode::CloseUpvalue } else { OpCode::Pop });
+                }
+                self.end_scope();
+            },
+            StatementKind::ForOf{ref name, declare, ref iterable, ref body} => {
+                self.compile_for_each(name, declare, iterable, OpCode::GetIterator, body);
+            },
+            StatementKind::ForIn{ref name, declare, ref object, ref body} => {
+                self.compile_for_each(name, declare, object, OpCode::GetKeys, body);
+            },
+            StatementKind::Break(ref label) => self.compile_jump(label, false),
+            StatementKind::Continue(ref label) => self.compile_jump(label, true),
+            StatementKind::Labeled{ref label, ref body} => {
+                self.state().labels.push(label.clone());
+                match body.kind {
+                    StatementKind::While{..} | StatementKind::DoWhile{..} | StatementKind::For{..} |
+                    StatementKind::ForOf{..} | StatementKind::ForIn{..} |
+                    StatementKind::Labeled{..} => self.compile_statement(body),
+                    _ => {
+                        self.begin_target(false, None);
+                        self.compile_statement(body);
+                        self.end_target();
+                    },
+                }
+            },
+            StatementKind::Throw{ref expr, ref pos} => {
+                self.compile_expression(expr);
+                self.span = pos.clone();
+                self.emit(OpCode::Throw);
+            },
+            StatementKind::Try{ref body, ref catch_name, ref catch_body, ref finally} => {
+                self.compile_try(body, catch_name, catch_body, finally);
+            },
+        }
     }
 }
 
-fn compile_assignment(script: &mut Vec<OpCode>, name: &String, expr: &Expression) {
-    compile_expression(script, expr);
-    script.push(OpCode::Val(Value::Str(name.clone())));
-    script.push(OpCode::Def);
+/// Compiles a whole script, read from `file`, into a function taking no
+/// arguments. The value of a trailing expression statement is left on the
+/// stack as the script's result.
+pub fn compile_script(statements: Vec<Statement>, file: &str) -> Function {
+    compile_script_checked(statements, file, &[]).0
 }
 
-pub fn compile_script(statements: Vec<Statement>) -> Vec<OpCode> {
-    let mut script = Vec::new();
-    for statement in statements {
-        match statement {
-            Statement::Expr(s) => compile_expression(&mut script, &s),
-            Statement::Assignment{ref name, ref expr} => {
-                compile_assignment(&mut script, name, expr);
-            },
-            Statement::If{cond, body} => {
-                compile_expression(&mut script, &cond);
-                let body = compile_script(body);
-                script.push(OpCode::JumpIfNot(body.len() as i32 + 1));
-                script.extend(body.iter().cloned());
-            },
-            Statement::IfElse{cond, body, else_body} => {
-                compile_expression(&mut script, &cond);
-                let body = compile_script(body);
-                script.push(OpCode::JumpIfNot(body.len() as i32 + 1));
-                script.extend(body.iter().cloned());
-                compile_expression(&mut script, &cond);
-                let else_body = compile_script(else_body);
-                script.push(OpCode::JumpIf(else_body.len() as i32 + 1));
-                script.extend(else_body.iter().cloned());
-            },
-            Statement::While{cond, body} => {
-                let start_len = script.len() as i32;
-                compile_expression(&mut script, &cond);
-                let body = compile_script(body);
-                script.push(OpCode::JumpIfNot(body.len() as i32 + 2));
-                script.extend(body.iter().cloned());
-                let end_len = script.len() as i32;
-                script.push(OpCode::Jump(start_len - end_len));
-            },
-        }
-    }
-    return script;
+/// Like `compile_script`, but also warns about globals the script reads
+/// without declaring them anywhere. Names in `known` are provided by the
+/// host and never warned about.
+pub fn compile_script_checked(statements: Vec<Statement>, file: &str, known: &[String])
+        -> (Function, Vec<Warning>) {
+    let mut compiler = Compiler{
+        functions: vec!(FunctionState::new(0)),
+        file: Rc::new(file.to_string()),
+        span: Span::default(),
+        declared: known.iter().cloned().collect(),
+        global_reads: Vec::new(),
+    };
+    if let Some((last, init)) = statements.split_last() {
+        compiler.compile_statements(init);
+        match last.kind {
+            StatementKind::Expr(ref e) if !matches!(e.kind, ExpressionKind::Function{name: Some(_), ..}) => {
+                compiler.span = last.span.clone();
+                compiler.compile_expression(e);
+            },
+            _ => compiler.compile_statement(last),
+        }
+    }
+    let warnings = compiler.global_reads.iter()
+        .filter(|(name, _)| !compiler.declared.contains(name))
+        .map(|(name, span)| Warning{
+            name: name.clone(),
+            location: Location{file: file.to_string(), line: span.line, col: span.col},
+            span: span.clone(),
+        })
+        .collect();
+    let state = compiler.functions.pop().unwrap();
+    let script = Function{
+        name: Some("<script>".to_string()),
+        arity: 0,
+        captures: Vec::new(),
+        body: state.code,
+        file: compiler.file,
+        lines: state.lines,
+        handlers: state.handlers,
+        kind: FunctionKind::Normal,
+    };
+    (script, warnings)
 }
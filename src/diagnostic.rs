@@ -0,0 +1,126 @@
+// Diagnostics: errors and warnings about a script, and rendering them with
+// the source they point at.
+use std::fmt;
+use std::fmt::Write;
+
+use lexer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Something to tell the user about a script, pointing at the source it is
+/// about when there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity:   Severity,
+    pub message:    String,
+    pub span:       Option<Span>,
+    pub notes:      Vec<String>,
+    pub help:       Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, span: Span) -> Diagnostic {
+        Diagnostic{
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: Some(span),
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn warning(message: &str, span: Span) -> Diagnostic {
+        Diagnostic{severity: Severity::Warning, ..Diagnostic::error(message, span)}
+    }
+
+    pub fn with_note(mut self, note: &str) -> Diagnostic {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Diagnostic {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// Renders the diagnostic the way rustc does: a header, the line of
+    /// `source` the span starts on with the span underlined, then any notes
+    /// and help. `color` adds ANSI colors for terminals.
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let reset = paint(RESET);
+        let mut out = String::new();
+        let _ = write!(out, "{}{}{}{}: {}{}", paint(self.severity.color()), self.severity.label(), reset,
+                       paint(BOLD), self.message, reset);
+        let span = match self.span {
+            Some(ref span) => span,
+            None => {
+                self.render_footer(&mut out, 0, color);
+                return out;
+            },
+        };
+        let gutter = span.line.to_string().len();
+        let _ = write!(out, "\n{:gutter$}{}-->{} {}:{}:{}", "", paint(BLUE), reset, span.file, span.line, span.col);
+        if let Some(before) = source.get(..span.start) {
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let rest = &source[line_start..];
+            let text = rest[..rest.find('\n').unwrap_or(rest.len())].trim_end_matches('\r');
+            // The underline stops at the end of the line for spans running
+            // over several.
+            let end = span.end.min(line_start + text.len()).max(span.start);
+            let width = source.get(span.start..end).map_or(1, |s| s.chars().count().max(1));
+            // Tabs are kept so the underline lines up however they display.
+            let pad: String = before[line_start..].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let _ = write!(out, "\n{:gutter$} {}|{}", "", paint(BLUE), reset);
+            let _ = write!(out, "\n{}{} |{} {}", paint(BLUE), span.line, reset, text);
+            let _ = write!(out, "\n{:gutter$} {}|{} {}{}{}{}", "", paint(BLUE), reset, pad,
+                           paint(self.severity.color()), "^".repeat(width), reset);
+        }
+        self.render_footer(&mut out, gutter, color);
+        out
+    }
+
+    fn render_footer(&self, out: &mut String, gutter: usize, color: bool) {
+        let (blue, bold, reset) = if color { (BLUE, BOLD, RESET) } else { ("", "", "") };
+        for note in &self.notes {
+            let _ = write!(out, "\n{:gutter$} {}={} {}note{}: {}", "", blue, reset, bold, reset, note);
+        }
+        if let Some(ref help) = self.help {
+            let _ = write!(out, "\n{:gutter$} {}={} {}help{}: {}", "", blue, reset, bold, reset, help);
+        }
+    }
+}
+
+/// The message and where it starts, for when there is no source to show.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(ref span) => write!(f, "{} at {}:{}", self.message, span.line, span.col),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
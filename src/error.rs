@@ -0,0 +1,165 @@
+use std::fmt;
+
+use value::Value;
+use lexer::Span;
+use diagnostic::{Diagnostic, Severity};
+
+/// A point in a script's source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file:   String,
+    pub line:   u32,
+    pub col:    u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// Something that went wrong while running a script. The variants are named
+/// after the JS error types they become.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// An operation was applied to values of the wrong types, which are
+    /// listed in operand order.
+    TypeError{message: String, operands: Vec<&'static str>},
+    ReferenceError{name: String},
+    RangeError{message: String},
+    /// A value thrown by the script, with how it reads as an uncaught
+    /// error.
+    Thrown{value: Value, description: String},
+}
+
+impl RuntimeError {
+    pub fn type_error(message: &str) -> RuntimeError {
+        RuntimeError::TypeError{message: message.to_string(), operands: Vec::new()}
+    }
+
+    pub fn range_error(message: &str) -> RuntimeError {
+        RuntimeError::RangeError{message: message.to_string()}
+    }
+
+    /// The name scripts know this kind of error by.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RuntimeError::TypeError{..} => "TypeError",
+            RuntimeError::ReferenceError{..} => "ReferenceError",
+            RuntimeError::RangeError{..} => "RangeError",
+            RuntimeError::Thrown{..} => "Error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            RuntimeError::TypeError{ref message, ..} => message.clone(),
+            RuntimeError::ReferenceError{ref name} => format!("{} is not defined", name),
+            RuntimeError::RangeError{ref message} => message.clone(),
+            RuntimeError::Thrown{ref description, ..} => description.clone(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::Thrown{ref description, ..} => write!(f, "{}", description),
+            _ => write!(f, "{}: {}", self.name(), self.message()),
+        }
+    }
+}
+
+/// One active call at the time of an error, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub function:   Option<String>,
+    pub location:   Option<Location>,
+    pub span:       Option<Span>,   // the source of the failing operation or call
+}
+
+impl StackFrame {
+    fn name(&self) -> &str {
+        self.function.as_ref().map_or("<anonymous>", |n| &n[..])
+    }
+
+    fn describe(&self) -> String {
+        match self.location {
+            Some(ref l) => format!("{} ({})", self.name(), l),
+            None => self.name().to_string(),
+        }
+    }
+}
+
+/// How many lines of a trace to show before only counting the frames left.
+const MAX_TRACE_LINES: usize = 20;
+
+/// A line for each of `frames`, after `verb`. A run of calls from the same
+/// place, as in a deep recursion, takes two lines, and frames past
+/// `MAX_TRACE_LINES` are only counted.
+fn trace_lines(frames: &[StackFrame], verb: &str) -> Vec<String> {
+    let plural = |n: usize, what: &str| if n == 1 { format!("1 more {}", what) } else { format!("{} more {}s", n, what) };
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < frames.len() {
+        if lines.len() >= MAX_TRACE_LINES {
+            lines.push(format!("… {}", plural(frames.len() - i, "frame")));
+            break;
+        }
+        let frame = &frames[i];
+        let run = frames[i..].iter()
+            .take_while(|f| f.function == frame.function && f.location == frame.location)
+            .count();
+        lines.push(format!("{} {}", verb, frame.describe()));
+        if run > 1 {
+            lines.push(format!("… {} {} {}", verb, frame.name(), plural(run - 1, "time")));
+        }
+        i += run;
+    }
+    lines
+}
+
+/// A runtime error that escaped the script, with the call stack it escaped
+/// from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    pub error:  RuntimeError,
+    pub trace:  Vec<StackFrame>,
+}
+
+impl ScriptError {
+    /// Where the failing operation is in the source.
+    #[cfg(test)]
+    pub fn location(&self) -> Option<&Location> {
+        self.trace.first().and_then(|f| f.location.as_ref())
+    }
+
+    /// The source of the failing operation.
+    pub fn span(&self) -> Option<&Span> {
+        self.trace.first().and_then(|f| f.span.as_ref())
+    }
+
+    /// The error pointing at the failing operation, with the calls that led
+    /// to it as notes.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let notes = trace_lines(self.trace.get(1..).unwrap_or(&[]), "called from");
+        Diagnostic{
+            severity: Severity::Error,
+            message: format!("Uncaught {}", self.error),
+            span: self.span().cloned(),
+            notes,
+            help: None,
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        for line in trace_lines(&self.trace, "at") {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
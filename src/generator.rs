@@ -0,0 +1,43 @@
+// The Generator prototype: the methods that drive a generator from scripts.
+use heap::Gc;
+use object::{Object, Native, PropertyMap};
+use value::Value;
+use vm::{VM, Resume, ITERATOR_KEY};
+use error::RuntimeError;
+
+/// Fills in the prototype object every generator looks its methods up on.
+pub fn install(vm: &mut VM, prototype: Gc) {
+    let mut props = PropertyMap::new();
+    add_native!("next", generator_next, vm, props);
+    add_native!("return", generator_return, vm, props);
+    add_native!("throw", generator_throw, vm, props);
+    add_native!(ITERATOR_KEY, generator_iterator, vm, props);
+    *vm.heap_mut().get_mut(prototype) = Object::Ordinary(props);
+}
+
+/// Resumes `this` and wraps what it produced in an iterator result.
+fn resume(vm: &mut VM, this: Value, how: Resume, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let value = args.into_iter().next().unwrap_or(Value::Undefined);
+    let (value, done) = vm.resume_generator(&this, how, value)?;
+    let mut props = PropertyMap::new();
+    props.set("value".to_string(), value);
+    props.set("done".to_string(), Value::Bool(done));
+    Ok(vm.alloc(Object::Ordinary(props)))
+}
+
+fn generator_next(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    resume(vm, this, Resume::Next, args)
+}
+
+fn generator_return(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    resume(vm, this, Resume::Return, args)
+}
+
+fn generator_throw(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    resume(vm, this, Resume::Throw, args)
+}
+
+/// Generators are their own iterators, so they work with `for...of`.
+fn generator_iterator(_vm: &mut VM, this: Value, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(this)
+}
@@ -1,5 +1,156 @@
 // A heap for our VM
+use std::mem;
 
+use object::Object;
+
+/// Collect once this many bytes are live if no threshold has been set.
+pub const DEFAULT_GC_THRESHOLD: usize = 1024 * 1024;
+
+/// A reference to an object living on the heap. Handles are only meaningful
+/// to the heap that handed them out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Gc(usize);
+
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    pub live_bytes:     usize,
+    pub live_objects:   usize,
+    pub collections:    usize,
+}
+
+struct Slot {
+    object: Object,
+    marked: bool,
+    size:   usize,  // as of when the object was last measured
+    dirty:  bool,   // whether it may have changed size since then
+}
+
+/// A mark-and-sweep collected object heap. The heap itself does not know
+/// which objects are reachable; whoever owns it passes the roots into
+/// `collect`.
+///
+/// Objects handed out through `get_mut` are measured again before the heap
+/// next decides whether to collect, so growing an array or an object counts
+/// towards the next collection. Strings are owned by the values holding them
+/// rather than by the heap, and only count as the size of a `Value`.
 pub struct Heap {
+    slots:      Vec<Option<Slot>>,
+    free:       Vec<usize>,
+    dirty:      Vec<usize>,
+    bytes:      usize,
+    threshold:  usize,
+    next_gc:    usize,
+    collections: usize,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap{
+            slots: Vec::new(),
+            free: Vec::new(),
+            dirty: Vec::new(),
+            bytes: 0,
+            threshold: DEFAULT_GC_THRESHOLD,
+            next_gc: DEFAULT_GC_THRESHOLD,
+            collections: 0,
+        }
+    }
+
+    /// Sets the minimum number of live bytes before a collection is due.
+    #[cfg(test)]
+    pub fn set_threshold(&mut self, bytes: usize) {
+        self.threshold = bytes;
+        self.next_gc = bytes;
+    }
+
+    pub fn alloc(&mut self, object: Object) -> Gc {
+        let size = mem::size_of::<Slot>() + object.extra_size();
+        self.bytes += size;
+        let slot = Slot{object, marked: false, size, dirty: false};
+        match self.free.pop() {
+            Some(i) => {
+                self.slots[i] = Some(slot);
+                Gc(i)
+            },
+            None => {
+                self.slots.push(Some(slot));
+                Gc(self.slots.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, handle: Gc) -> &Object {
+        match self.slots[handle.0] {
+            Some(ref slot) => &slot.object,
+            None => panic!("use of a collected object"),
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: Gc) -> &mut Object {
+        match self.slots[handle.0] {
+            Some(ref mut slot) => {
+                if !slot.dirty {
+                    slot.dirty = true;
+                    self.dirty.push(handle.0);
+                }
+                &mut slot.object
+            },
+            None => panic!("use of a collected object"),
+        }
+    }
+
+    /// Brings the sizes of objects that may have changed up to date.
+    fn measure(&mut self) {
+        for i in self.dirty.drain(..) {
+            if let Some(ref mut slot) = self.slots[i] {
+                let size = mem::size_of::<Slot>() + slot.object.extra_size();
+                self.bytes = self.bytes - slot.size + size;
+                slot.size = size;
+                slot.dirty = false;
+            }
+        }
+    }
+
+    pub fn should_collect(&mut self) -> bool {
+        self.measure();
+        self.bytes >= self.next_gc
+    }
+
+    #[cfg(test)]
+    pub fn stats(&mut self) -> HeapStats {
+        self.measure();
+        HeapStats{
+            live_bytes: self.bytes,
+            live_objects: self.slots.len() - self.free.len(),
+            collections: self.collections,
+        }
+    }
+
+    /// Frees every object not reachable from `roots`.
+    pub fn collect<I: Iterator<Item=Gc>>(&mut self, roots: I) {
+        self.measure();
+        let mut grey: Vec<Gc> = roots.collect();
+        while let Some(handle) = grey.pop() {
+            let slot = self.slots[handle.0].as_mut().unwrap();
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+            slot.object.trace(&mut grey);
+        }
 
-};
+        for (i, entry) in self.slots.iter_mut().enumerate() {
+            let live = match *entry {
+                Some(ref mut slot) => mem::replace(&mut slot.marked, false),
+                None => continue,
+            };
+            if !live {
+                self.bytes -= entry.take().unwrap().size;
+                self.free.push(i);
+            }
+        }
+        self.collections += 1;
+        self.next_gc = self.threshold.max(self.bytes * 2);
+    }
+}
@@ -1,13 +1,20 @@
 #![allow(dead_code)]
+use std::mem;
+use std::rc::Rc;
+
+use unicode_ident::{is_xid_start, is_xid_continue};
+
+use diagnostic::Diagnostic;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    Int,
-    Float,
+    Number,
     Str,
     Identifier,
     Equals,
     Let,
+    Const,
+    In,
     Function,
     Return,
     LPar,
@@ -23,9 +30,29 @@ pub enum TokenType {
     If,
     Else,
     While,
+    For,
+    Do,
+    Break,
+    Continue,
     True,
     False,
     Semicolon,
+    Question,
+    Not,
+    TypeOf,
+    Void,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Yield,
+    Async,
+    Await,
+    /// A piece of a template literal that a `${` substitution follows.
+    TemplatePart,
+    /// The last piece of a template literal, or all of one without
+    /// substitutions.
+    TemplateEnd,
 }
 
 #[derive(Debug, Clone)]
@@ -40,50 +67,209 @@ pub enum BinOp {
     LtEq,
     Gt,
     GtEq,
+    And,
+    Or,
+    Nullish,
+}
+
+impl BinOp {
+    /// Whether the operator short-circuits.
+    pub fn is_logical(&self) -> bool {
+        matches!(*self, BinOp::And | BinOp::Or | BinOp::Nullish)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+    Not,
+    TypeOf,
+    Void,
+}
+
+impl TokenType {
+    /// How to refer to this kind of token in a message.
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            TokenType::Number => "a number",
+            TokenType::Str => "a string",
+            TokenType::Identifier => "an identifier",
+            TokenType::BinOp => "an operator",
+            TokenType::TemplatePart | TokenType::TemplateEnd => "a template literal",
+            TokenType::Equals => "`=`",
+            TokenType::Let => "`let`",
+            TokenType::Const => "`const`",
+            TokenType::In => "`in`",
+            TokenType::Function => "`function`",
+            TokenType::Return => "`return`",
+            TokenType::LPar => "`(`",
+            TokenType::RPar => "`)`",
+            TokenType::LCBrace => "`{`",
+            TokenType::RCBrace => "`}`",
+            TokenType::LBracket => "`[`",
+            TokenType::RBracket => "`]`",
+            TokenType::Colon => "`:`",
+            TokenType::Comma => "`,`",
+            TokenType::Period => "`.`",
+            TokenType::If => "`if`",
+            TokenType::Else => "`else`",
+            TokenType::While => "`while`",
+            TokenType::For => "`for`",
+            TokenType::Do => "`do`",
+            TokenType::Break => "`break`",
+            TokenType::Continue => "`continue`",
+            TokenType::True => "`true`",
+            TokenType::False => "`false`",
+            TokenType::Semicolon => "`;`",
+            TokenType::Question => "`?`",
+            TokenType::Not => "`!`",
+            TokenType::TypeOf => "`typeof`",
+            TokenType::Void => "`void`",
+            TokenType::Throw => "`throw`",
+            TokenType::Try => "`try`",
+            TokenType::Catch => "`catch`",
+            TokenType::Finally => "`finally`",
+            TokenType::Yield => "`yield`",
+            TokenType::Async => "`async`",
+            TokenType::Await => "`await`",
+        }
+    }
+}
+
+/// A line and column in the source, both counting from one.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub line:   u32,
+    pub col:    u32,
+}
+
+/// A range of source text: byte offsets into the file, along with the line
+/// and column it starts at.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Span {
+    pub file:   Rc<String>,
+    pub start:  usize,
+    pub end:    usize,
+    pub line:   u32,
+    pub col:    u32,
+}
+
+impl Span {
+    /// Where the span starts.
+    pub fn position(&self) -> Position {
+        Position{line: self.line, col: self.col}
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        Span{end: other.end.max(self.end), ..self.clone()}
+    }
+}
+
+/// A line or block comment, kept as trivia so that tools such as a
+/// formatter can put it back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text:       String,     // including the `//` or `/* */`
+    pub position:   Position,
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     token_type : TokenType,
     value      : String,
-    row        : u32,
-    col        : u32,
+    span       : Span,
+    comments   : Vec<Comment>,  // the comments just before the token
+    number     : f64,           // the value of a number token
 }
 
 impl Token {
 
-    fn new(token_type: TokenType, value: String, row: u32, col: u32) -> Token {
-        return Token{token_type: token_type, value: value, row: row, col: col};
+    fn new(token_type: TokenType, value: String, span: Span) -> Token {
+        Token{token_type, value, span, comments: Vec::new(), number: 0.0}
+    }
+
+    pub fn token_type(&self) -> &TokenType {
+        &self.token_type
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// The value of a number token.
+    pub fn number(&self) -> f64 {
+        self.number
+    }
+
+    /// Where the token starts.
+    pub fn position(&self) -> Position {
+        self.span.position()
+    }
+
+    /// The source text the token was read from.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// How to refer to the token in a message: its text, unless it's a
+    /// string whose text could be anything.
+    pub fn describe(&self) -> String {
+        match self.token_type {
+            TokenType::Str | TokenType::TemplatePart | TokenType::TemplateEnd => {
+                self.token_type.describe().to_string()
+            },
+            _ => format!("`{}`", self.value),
+        }
+    }
+
+    /// The comments between the previous token and this one.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
     }
 
 }
 
 pub struct Lexer {
     tokens: Vec<Token>,
+    file:   Rc<String>,     // the name of the file being lexed, for spans
     lines:  u32,
+    line_start: usize,  // where the line being lexed starts in the source
     start_pos: usize,
+    start_line: (u32, usize),   // `lines` and `line_start` at `start_pos`
+    // A byte offset on the line being lexed and its column, so columns can
+    // be counted in characters without rescanning the whole line.
+    column_mark: (usize, u32),
     cursor: usize,
     top: usize,
+    // For each `${` substitution being lexed, innermost last, how many of
+    // its braces are open.
+    templates: Vec<usize>,
+    // Comments waiting for the next token, and those after the last one.
+    comments: Vec<Comment>,
+    trailing: Vec<Comment>,
 }
 
 impl Lexer {
     pub fn new() -> Lexer {
-        return Lexer{
-            tokens:     Vec::new(),
-            lines:      0,
-            start_pos:  0,
-            cursor:     0,
-            top:        0,
-        }
+        Lexer::from(Vec::new())
     }
 
     pub fn from(tokens: Vec<Token>) -> Lexer {
-        return Lexer{
-            tokens:     tokens,
-            lines:      0,
+        Lexer{
+            tokens,
+            file:       Rc::new("<input>".to_string()),
+            lines:      1,
+            line_start: 0,
             start_pos:  0,
+            start_line: (1, 0),
+            column_mark: (0, 1),
             cursor:     0,
             top:        0,
+            templates:  Vec::new(),
+            comments:   Vec::new(),
+            trailing:   Vec::new(),
         }
     }
 
@@ -91,92 +277,413 @@ impl Lexer {
         return &self.tokens;
     }
 
+    /// The comments after the last token.
+    pub fn trailing_comments(&self) -> &[Comment] {
+        &self.trailing
+    }
+
+    /// Makes a lexer for the source of `file`.
+    pub fn with_file(file: &str) -> Lexer {
+        Lexer{file: Rc::new(file.to_string()), ..Lexer::new()}
+    }
+
     pub fn reset(&mut self) {
-        self.tokens = Vec::new();
-        self.lines = 0;
-        self.start_pos = 0;
-        self.cursor = 0;
-        self.top = 0;
-    }
-
-    fn add_token(&mut self, token_type: TokenType, line: &str) {
-        self.tokens.push(
-            Token::new(
-                token_type,
-                String::from(&line[self.start_pos..self.cursor]),
-                self.lines,
-                self.cursor as u32
-            )
-        );
+        *self = Lexer::new();
+    }
+
+    fn add_token(&mut self, token_type: TokenType, src: &str) {
+        let value = String::from(&src[self.start_pos..self.cursor]);
+        self.push_token(token_type, value, src);
+    }
+
+    /// Adds a token starting at `start_pos` whose value isn't just its
+    /// source text.
+    fn push_token(&mut self, token_type: TokenType, value: String, src: &str) {
+        let span = self.span(src);
+        let mut token = Token::new(token_type, value, span);
+        token.comments = mem::take(&mut self.comments);
+        self.tokens.push(token);
         // go ahead and fix our substring window
+        self.mark_start();
+    }
+
+    /// Starts the next token at the cursor.
+    fn mark_start(&mut self) {
         self.start_pos = self.cursor;
+        self.start_line = (self.lines, self.line_start);
+    }
+
+    /// The line and column of `start_pos`, counting columns in characters.
+    fn start_position(&mut self, src: &str) -> Position {
+        let (line, line_start) = self.start_line;
+        let (mark, col) = self.column_mark;
+        // The mark only helps if it's on the same line, before the token.
+        let (mark, col) = if mark >= line_start && mark <= self.start_pos { (mark, col) } else { (line_start, 1) };
+        let col = col + src[mark..self.start_pos].chars().count() as u32;
+        self.column_mark = (self.start_pos, col);
+        Position{line, col}
     }
 
-    fn chr(&self, line: &str) -> char {
-        match line.chars().nth(self.cursor) {
-            Some(value) => return value,
-            None => return ' ',
+    /// The span from `start_pos` to the cursor.
+    fn span(&mut self, src: &str) -> Span {
+        let position = self.start_position(src);
+        Span{
+            file: self.file.clone(),
+            start: self.start_pos,
+            end: self.cursor,
+            line: position.line,
+            col: position.col,
         }
     }
-    fn peek(&self, line: &str) -> char {
-        match line.chars().nth(self.cursor + 1) {
-            Some(value) => return value,
-            None => return ' ',
+
+    /// The character at the cursor, or `'\0'` at the end.
+    fn chr(&self, src: &str) -> char {
+        src[self.cursor..].chars().next().unwrap_or('\0')
+    }
+    /// The character after the one at the cursor.
+    fn peek(&self, src: &str) -> char {
+        src[self.cursor..].chars().nth(1).unwrap_or('\0')
+    }
+
+    /// Moves past the current character, keeping count of lines.
+    fn advance(&mut self, src: &str) {
+        let c = self.chr(src);
+        if c == '\n' {
+            self.lines += 1;
+            self.line_start = self.cursor + 1;
         }
+        self.cursor += c.len_utf8();
+    }
+
+    /// Skips whitespace and comments, keeping the comments for the next
+    /// token.
+    fn skip_whitespace(&mut self, src: &str) -> Result<(), Diagnostic> {
+        loop {
+            while self.cursor < src.len() && Lexer::is_whitespace(self.chr(src)) {
+                self.advance(src);
+            }
+            self.mark_start();
+            let block = match (self.chr(src), self.peek(src)) {
+                ('/', '/') => false,
+                ('/', '*') => true,
+                _ => return Ok(()),
+            };
+            let position = self.start_position(src);
+            if block {
+                let end = match src[self.cursor + 2..].find("*/") {
+                    Some(i) => self.cursor + 2 + i + 2,
+                    None => return Err(self.error(src, "unterminated comment")),
+                };
+                while self.cursor < end {
+                    self.advance(src);
+                }
+            } else {
+                while self.cursor < src.len() && self.chr(src) != '\n' {
+                    self.advance(src);
+                }
+            }
+            let text = src[self.start_pos..self.cursor].to_string();
+            self.comments.push(Comment{text, position});
+        }
+    }
+
+    /// An error about the token from `start_pos` to the cursor.
+    fn error(&mut self, src: &str, message: &str) -> Diagnostic {
+        Diagnostic::error(message, self.span(src))
     }
 
-    fn skip_whitespace(&mut self, line: &str) {
-        while self.chr(line) == ' ' && self.cursor < line.len() - 1 {
+    /// Reads the escape sequence at the cursor, just past a backslash, onto
+    /// the end of `value`.
+    fn escape(&mut self, src: &str, value: &mut String) -> Result<(), Diagnostic> {
+        let c = self.chr(src);
+        let simple = match c {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            'b' => Some('\u{8}'),
+            'f' => Some('\u{c}'),
+            'v' => Some('\u{b}'),
+            '0' if !self.peek(src).is_ascii_digit() => Some('\0'),
+            _ => None,
+        };
+        if let Some(escaped) = simple {
+            value.push(escaped);
             self.cursor += 1;
-            self.start_pos += 1;
+            return Ok(());
         }
+        let digits = match c {
+            'x' => 2,
+            'u' if self.peek(src) != '{' => 4,
+            'u' => {
+                let close = src[self.cursor..].find('}').map(|i| self.cursor + i);
+                let code = close.and_then(|close| {
+                    let hex = &src[self.cursor + 2..close];
+                    if hex.is_empty() || hex.len() > 6 { None } else { u32::from_str_radix(hex, 16).ok() }
+                });
+                return match (code.and_then(::std::char::from_u32), close) {
+                    (Some(escaped), Some(close)) => {
+                        value.push(escaped);
+                        self.cursor = close + 1;
+                        Ok(())
+                    },
+                    _ => Err(self.error(src, "invalid unicode escape")),
+                };
+            },
+            '\n' => {
+                // A backslash before a line break continues the line.
+                self.advance(src);
+                return Ok(());
+            },
+            _ if self.cursor >= src.len() => return Ok(()),
+            _ => {
+                // Anything else stands for itself.
+                let len = src[self.cursor..].chars().next().map_or(1, char::len_utf8);
+                value.push_str(&src[self.cursor..self.cursor + len]);
+                self.cursor += len;
+                return Ok(());
+            },
+        };
+        let code = src.get(self.cursor + 1..self.cursor + 1 + digits)
+            .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(::std::char::from_u32);
+        match code {
+            Some(escaped) => {
+                value.push(escaped);
+                self.cursor += 1 + digits;
+                Ok(())
+            },
+            None if c == 'x' => Err(self.error(src, "invalid hexadecimal escape")),
+            None => Err(self.error(src, "invalid unicode escape")),
+        }
+    }
+
+    /// Reads a quoted string literal, starting on its opening quote.
+    fn string(&mut self, src: &str) -> Result<(), Diagnostic> {
+        let delim = self.chr(src);
+        self.cursor += 1;
+        let mut value = String::new();
+        let mut chunk = self.cursor;
+        loop {
+            if self.cursor >= src.len() || self.chr(src) == '\n' {
+                let error = self.error(src, "unterminated string literal");
+                return Err(error.with_help("use a template literal (`...`) for text over several lines"));
+            }
+            match self.chr(src) {
+                c if c == delim => break,
+                '\\' => {
+                    value.push_str(&src[chunk..self.cursor]);
+                    self.cursor += 1;
+                    self.escape(src, &mut value)?;
+                    chunk = self.cursor;
+                },
+                _ => self.advance(src),
+            }
+        }
+        value.push_str(&src[chunk..self.cursor]);
+        self.cursor += 1;
+        self.push_token(TokenType::Str, value, src);
+        Ok(())
+    }
+
+    /// Reads a piece of a template literal, starting just past the backtick
+    /// or the `}` before it. It may span lines.
+    fn template(&mut self, src: &str) -> Result<(), Diagnostic> {
+        let mut value = String::new();
+        let mut chunk = self.cursor;
+        let token_type = loop {
+            if self.cursor >= src.len() {
+                return Err(self.error(src, "unterminated template literal"));
+            }
+            match self.chr(src) {
+                '`' => break TokenType::TemplateEnd,
+                '$' if self.peek(src) == '{' => break TokenType::TemplatePart,
+                '\\' => {
+                    value.push_str(&src[chunk..self.cursor]);
+                    self.cursor += 1;
+                    self.escape(src, &mut value)?;
+                    chunk = self.cursor;
+                },
+                _ => self.advance(src),
+            }
+        };
+        value.push_str(&src[chunk..self.cursor]);
+        if token_type == TokenType::TemplatePart {
+            self.cursor += 2;
+            self.templates.push(0);
+        } else {
+            self.cursor += 1;
+        }
+        self.push_token(token_type, value, src);
+        Ok(())
     }
 
-    // Matches a string to a binop, along with its precedence
-    pub fn bin_op(v: &str) -> Option<(BinOp, u8)> {
+    /// Reads a run of digits in `radix`, which `_` separators may split up,
+    /// returning whether there were any.
+    fn digits(&mut self, src: &str, radix: u32) -> Result<bool, Diagnostic> {
+        let start = self.cursor;
+        loop {
+            let c = self.chr(src);
+            if c == '_' {
+                if self.cursor == start || !self.peek(src).is_digit(radix) {
+                    return Err(self.error(src, "numeric separators are only allowed between digits"));
+                }
+            } else if !c.is_digit(radix) {
+                return Ok(self.cursor > start);
+            }
+            self.cursor += 1;
+        }
+    }
+
+    /// Reads a numeric literal: a decimal with an optional fraction and
+    /// exponent, or a hexadecimal, octal or binary integer.
+    fn number(&mut self, src: &str) -> Result<(), Diagnostic> {
+        let radix = match (self.chr(src), self.peek(src)) {
+            ('0', 'x') | ('0', 'X') => 16,
+            ('0', 'o') | ('0', 'O') => 8,
+            ('0', 'b') | ('0', 'B') => 2,
+            _ => 10,
+        };
+        let value = if radix == 10 {
+            self.digits(src, 10)?;
+            let int = &src[self.start_pos..self.cursor];
+            if int.len() > 1 && int.starts_with('0') {
+                return Err(self.error(src, "leading zeros are not allowed"));
+            }
+            if self.chr(src) == '.' && self.peek(src).is_ascii_digit() {
+                self.cursor += 1;
+                self.digits(src, 10)?;
+            }
+            if self.chr(src) == 'e' || self.chr(src) == 'E' {
+                self.cursor += 1;
+                if self.chr(src) == '+' || self.chr(src) == '-' {
+                    self.cursor += 1;
+                }
+                if !self.digits(src, 10)? && !Lexer::is_identifier_part(self.chr(src)) {
+                    return Err(self.error(src, "missing digits in numeric literal"));
+                }
+            }
+            let text: String = src[self.start_pos..self.cursor].chars().filter(|&c| c != '_').collect();
+            text.parse::<f64>().unwrap_or(f64::NAN)
+        } else {
+            self.cursor += 2;
+            let start = self.cursor;
+            if !self.digits(src, radix)? && !Lexer::is_identifier_part(self.chr(src)) {
+                return Err(self.error(src, "missing digits in numeric literal"));
+            }
+            Lexer::binary_value(&src[start..self.cursor], radix)
+        };
+        // Catch things like `0b12` and `3in`.
+        if self.chr(src).is_ascii_digit() {
+            return Err(self.error(src, "invalid digit in numeric literal"));
+        }
+        if Lexer::is_identifier_part(self.chr(src)) {
+            return Err(self.error(src, "identifier starts immediately after numeric literal"));
+        }
+        self.add_token(TokenType::Number, src);
+        if let Some(token) = self.tokens.last_mut() {
+            token.number = value;
+        }
+        Ok(())
+    }
+
+    /// The value of integer digits in a radix of 2, 8 or 16, rounded to the
+    /// nearest double once rather than after every digit.
+    fn binary_value(digits: &str, radix: u32) -> f64 {
+        let bits = radix.trailing_zeros();
+        // The leading 55 bits, then how many bits were dropped after them and
+        // whether any of those were set.
+        let mut mantissa: u64 = 0;
+        let mut dropped: i32 = 0;
+        let mut sticky = false;
+        for digit in digits.chars().filter_map(|c| c.to_digit(radix)) {
+            for i in (0..bits).rev() {
+                let bit = u64::from(digit >> i & 1);
+                if mantissa < 1 << 54 {
+                    mantissa = mantissa << 1 | bit;
+                } else {
+                    dropped = dropped.saturating_add(1);
+                    sticky |= bit != 0;
+                }
+            }
+        }
+        // Folding the dropped bits into the lowest kept one lets the integer
+        // conversion round half to even correctly.
+        (mantissa | u64::from(sticky)) as f64 * 2f64.powi(dropped)
+    }
+
+    /// Unicode whitespace, plus the byte order mark, which JavaScript also
+    /// treats as a space.
+    fn is_whitespace(c: char) -> bool {
+        c.is_whitespace() || c == '\u{feff}'
+    }
+
+    fn is_identifier_start(c: char) -> bool {
+        c == '$' || c == '_' || is_xid_start(c)
+    }
+
+    fn is_identifier_part(c: char) -> bool {
+        c == '$' || is_xid_continue(c)
+    }
+
+    // Matches a string to a binop
+    pub fn bin_op(v: &str) -> Option<BinOp> {
         match v {
-            "+"  => Some((BinOp::Plus, 2)),
-            "-"  => Some((BinOp::Min, 2)),
-            "*"  => Some((BinOp::Mul, 1)),
-            "/"  => Some((BinOp::Div, 1)),
-            "==" => Some((BinOp::EqEq, 9)),
-            "!=" => Some((BinOp::NotEq, 9)),
-            "<"  => Some((BinOp::Lt, 9)),
-            "<=" => Some((BinOp::LtEq, 9)),
-            ">"  => Some((BinOp::Gt, 9)),
-            ">=" => Some((BinOp::GtEq, 9)),
+            "+"  => Some(BinOp::Plus),
+            "-"  => Some(BinOp::Min),
+            "*"  => Some(BinOp::Mul),
+            "/"  => Some(BinOp::Div),
+            "==" => Some(BinOp::EqEq),
+            "!=" => Some(BinOp::NotEq),
+            "<"  => Some(BinOp::Lt),
+            "<=" => Some(BinOp::LtEq),
+            ">"  => Some(BinOp::Gt),
+            ">=" => Some(BinOp::GtEq),
+            "&&" => Some(BinOp::And),
+            "||" => Some(BinOp::Or),
+            "??" => Some(BinOp::Nullish),
             _ => None
         }
     }
 
-    fn is_binop(&mut self, line: &str) -> bool {
-        let mut is_binop  = false;
-        // offset corresponds to the widest binop character length
-        let mut offset = 2;
-        while self.cursor + offset >= line.len() {
-            offset -= 1;
-        }
-        while offset > 0 && !is_binop {
-            match Lexer::bin_op(&line[self.start_pos..self.cursor + offset]) {
-                Some(_) => is_binop = true,
-                None    => ()
+    /// Moves past the operator at the cursor, if there is one.
+    fn is_binop(&mut self, src: &str) -> bool {
+        // try the widest operators first
+        for width in (1..3).rev() {
+            let op = src.get(self.cursor..self.cursor + width);
+            if op.and_then(Lexer::bin_op).is_some() {
+                self.cursor += width;
+                return true;
             }
-            offset -= 1;
         }
-        self.cursor += offset;
-        return is_binop;
+        false
     }
 
     fn keyword(&mut self, line: &str) -> Option<TokenType> {
         match &line[self.start_pos..self.cursor] {
             "let"       => Some(TokenType::Let),
+            "const"     => Some(TokenType::Const),
+            "in"        => Some(TokenType::In),
             "if"        => Some(TokenType::If),
             "else"      => Some(TokenType::Else),
             "while"     => Some(TokenType::While),
+            "for"       => Some(TokenType::For),
+            "do"        => Some(TokenType::Do),
+            "break"     => Some(TokenType::Break),
+            "continue"  => Some(TokenType::Continue),
             "true"      => Some(TokenType::True),
             "false"     => Some(TokenType::False),
             "function"  => Some(TokenType::Function),
             "return"    => Some(TokenType::Return),
+            "typeof"    => Some(TokenType::TypeOf),
+            "void"      => Some(TokenType::Void),
+            "throw"     => Some(TokenType::Throw),
+            "try"       => Some(TokenType::Try),
+            "catch"     => Some(TokenType::Catch),
+            "finally"   => Some(TokenType::Finally),
+            "yield"     => Some(TokenType::Yield),
+            "async"     => Some(TokenType::Async),
+            "await"     => Some(TokenType::Await),
              _ => None
         }
     }
@@ -199,6 +706,11 @@ impl Lexer {
         return false;
     }
 
+    /// The index of the current token.
+    pub fn position(&self) -> usize {
+        self.top
+    }
+
     pub fn tokens_remaining(&self) -> usize {
         return self.tokens.len() - self.top;
     }
@@ -210,6 +722,16 @@ impl Lexer {
         return &self.tokens[self.top];
     }
 
+    /// The token after the current one, if there is one.
+    pub fn peek_token(&self) -> Option<&Token> {
+        self.tokens.get(self.top + 1)
+    }
+
+    /// The type of the token after the current one, if there is one.
+    pub fn peek_type(&self) -> Option<&TokenType> {
+        self.peek_token().map(|t| &t.token_type)
+    }
+
     pub fn curr_type(&self) -> &TokenType {
         return &self.curr_token().token_type;
     }
@@ -222,148 +744,168 @@ impl Lexer {
         return self.curr_token().token_type == t;
     }
 
-    pub fn match_token(&self, t: TokenType) -> Result<&Token, String> {
-        if self.curr_token().token_type == t {
-            return Ok(self.curr_token());
+    pub fn match_token(&self, t: TokenType) -> Result<&Token, Diagnostic> {
+        match self.tokens.get(self.top) {
+            Some(token) if token.token_type == t => Ok(token),
+            _ => Err(self.unexpected(t.describe())),
         }
-        return Err(format!("expected: {:?} , found: {:?}", t, self.curr_token()));
     }
 
-    pub fn tokenize(&mut self, lines: String) -> Result<(), String>{
-        for line in lines.lines() {
-            self.lines += 1;
-            self.start_pos = 0;
-            self.cursor = 0;
+    /// An error for finding the current token where `expected` should be.
+    pub fn unexpected(&self, expected: &str) -> Diagnostic {
+        let token = match self.tokens.get(self.top) {
+            Some(token) => token,
+            // The input ran out, so point at how far it got.
+            None => {
+                let span = self.tokens.last().map_or_else(Span::default, |t| t.span.clone());
+                let message = format!("expected {}, found the end of the input", expected);
+                return Diagnostic::error(&message, span);
+            },
+        };
+        Diagnostic::error(&format!("expected {}, found {}", expected, token.describe()), token.span.clone())
+    }
+
+    pub fn tokenize(&mut self, src: String) -> Result<(), Diagnostic>{
+        let src = &src[..];
+        // A byte order mark at the start isn't part of the first line.
+        if self.cursor == 0 && src.starts_with('\u{feff}') {
+            self.cursor = '\u{feff}'.len_utf8();
+            self.line_start = self.cursor;
+        }
+        loop {
+            // skip whitespace
+            self.skip_whitespace(src)?;
+            if self.cursor >= src.len() {
+                self.trailing = mem::take(&mut self.comments);
+                break;
+            }
 
-            while self.cursor < line.len() {
-                // skip whitespace
-                self.skip_whitespace(line);
+            // Strings
+            if self.chr(src) == '"' || self.chr(src) == '\'' {
+                self.string(src)?;
+                continue;
+            }
+            if self.chr(src) == '`' {
+                self.cursor += 1;
+                self.template(src)?;
+                continue;
+            }
+
+            // Identifiers .. Keywords
+            if Lexer::is_identifier_start(self.chr(src)) {
+                while Lexer::is_identifier_part(self.chr(src)) {
+                    self.advance(src);
+                }
+                match self.keyword(src) {
+                    Some(tt) => self.add_token(tt, src),
+                    None     => self.add_token(TokenType::Identifier, src)
+                }
+                continue;
+            }
 
-                // Strings
-                if self.chr(line) == '"' || self.chr(line) == '\'' {
-                    let delim = self.chr(line);
-                    self.start_pos += 1;
+            // Numbers; a leading minus is an operator
+            if self.chr(src).is_ascii_digit() ||
+               self.chr(src) == '.' && self.peek(src).is_ascii_digit() {
+                self.number(src)?;
+                continue;
+            }
+
+            // BinOps || UnaryOps
+            if self.is_binop(src) {
+                self.add_token(TokenType::BinOp, src);
+                continue;
+            }
+
+            // MISC
+            match self.chr(src) {
+                '='  => {
                     self.cursor += 1;
-                    // TODO: handle escaping
-                    while self.chr(line) != delim {
-                        self.cursor += 1;
-                    }
-                    self.add_token(TokenType::Str, line);
-                    self.start_pos += 1;
+                    self.add_token(TokenType::Equals, src);
+                    continue;
+                },
+                '('  => {
                     self.cursor += 1;
+                    self.add_token(TokenType::LPar, src);
                     continue;
-                }
-
-                // Identifiers .. Keywords
-                if self.chr(line).is_alphabetic() {
-                    while self.chr(line).is_alphabetic() {
-                        self.cursor += 1;
-                    }
-                    match self.keyword(line) {
-                        Some(tt) => self.add_token(tt, line),
-                        None     => self.add_token(TokenType::Identifier, line)
-                    }
+                },
+                ')'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::RPar, src);
                     continue;
-                }
-
-                // IntS 'N FloatS
-                if self.chr(line).is_digit(10) ||
-                   self.chr(line) == '.' && self.peek(line).is_digit(10) ||
-                   self.chr(line) == '-' && self.peek(line).is_digit(10) {
-                    if self.chr(line) == '-' {
-                        self.cursor += 1;
-                    }
-                    while self.chr(line).is_digit(10) {
-                        self.cursor += 1;
+                },
+                '{'  => {
+                    if let Some(open) = self.templates.last_mut() {
+                        *open += 1;
                     }
-                    if self.chr(line) == '.' && self.peek(line).is_digit(10) {
-                        self.cursor += 1;
-                        while self.chr(line).is_digit(10) {
-                            self.cursor += 1;
-                        }
-                        self.add_token(TokenType::Float, line);
-                        continue;
+                    self.cursor += 1;
+                    self.add_token(TokenType::LCBrace, src);
+                    continue;
+                },
+                '}'  => {
+                    self.cursor += 1;
+                    match self.templates.last_mut() {
+                        // This closes a substitution, so the template goes on.
+                        Some(&mut 0) => {
+                            self.templates.pop();
+                            self.template(src)?;
+                        },
+                        Some(open) => {
+                            *open -= 1;
+                            self.add_token(TokenType::RCBrace, src);
+                        },
+                        None => self.add_token(TokenType::RCBrace, src),
                     }
-                    self.add_token(TokenType::Int, line);
                     continue;
-                }
-
-                // BinOps || UnaryOps
-                if self.is_binop(line) {
+                },
+                '['  => {
                     self.cursor += 1;
-                    self.add_token(TokenType::BinOp, line);
+                    self.add_token(TokenType::LBracket, src);
                     continue;
+                },
+                ']'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::RBracket, src);
+                    continue;
+                },
+                ':'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Colon, src);
+                    continue;
+                },
+                ','  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Comma, src);
+                    continue;
+                },
+                '.'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Period, src);
+                    continue;
+                },
+                ';'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Semicolon, src);
+                    continue;
+                },
+                '!'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Not, src);
+                    continue;
+                },
+                '?'  => {
+                    self.cursor += 1;
+                    self.add_token(TokenType::Question, src);
+                    continue;
+                },
+                c    => {
+                    self.advance(src);
+                    return Err(self.error(src, &format!("unexpected character `{}`", c)));
                 }
-
-                // MISC
-                match self.chr(line) {
-                    '='  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::Equals, line);
-                        continue;
-                    },
-                    '('  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::LPar, line);
-                        continue;
-                    },
-                    ')'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::RPar, line);
-                        continue;
-                    },
-                    '{'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::LCBrace, line);
-                        continue;
-                    },
-                    '}'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::RCBrace, line);
-                        continue;
-                    },
-                    '['  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::LBracket, line);
-                        continue;
-                    },
-                    ']'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::RBracket, line);
-                        continue;
-                    },
-                    ':'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::Colon, line);
-                        continue;
-                    },
-                    ','  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::Comma, line);
-                        continue;
-                    },
-                    '.'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::Period, line);
-                        continue;
-                    },
-                    ';'  => {
-                        self.cursor += 1;
-                        self.add_token(TokenType::Semicolon, line);
-                        continue;
-                    },
-                    '\n' => break,
-                    '\0' => break,
-                    ' '  => break,
-                    _    => {
-                        return Err(format!("unknown symbol: {}, ln: {} col: {}",
-                                            &line[self.start_pos..line.len()],
-                                            self.lines, self.start_pos));
-                    }
-                }
-
             }
         }
+        if !self.templates.is_empty() {
+            return Err(self.error(src, "unterminated template literal"));
+        }
         return Ok(());
     }
 }
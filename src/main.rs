@@ -1,12 +1,31 @@
+// The original code keeps its own style rather than the one these lints ask
+// for.
+#![allow(clippy::needless_return, clippy::len_zero, clippy::redundant_static_lifetimes,
+         clippy::needless_borrowed_reference, clippy::legacy_numeric_constants)]
+
+extern crate unicode_ident;
+
 use std::env;
 use std::process;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::collections::HashMap;
+use std::io::IsTerminal;
 
 use value::Value;
+use error::{RuntimeError, ScriptError};
+use diagnostic::Diagnostic;
+#[cfg(test)]
+use error::Location;
 use object::{Object, Native};
+#[cfg(test)]
+use object::PromiseState;
+#[cfg(test)]
+use timer::{Clock, VirtualClock};
+#[cfg(test)]
+use lexer::{Lexer, Comment, Position, Span};
+#[cfg(test)]
+use ast::{StatementKind, ExpressionKind};
 use compiler::*;
 use parser::*;
 use vm::*;
@@ -20,42 +39,122 @@ mod lexer;
 mod value;
 mod ast;
 mod vm;
+mod heap;
+mod array;
+mod generator;
+mod promise;
+mod timer;
+mod error;
+mod diagnostic;
 
 const VERSION: &'static str = "0.0.0";
 
 // A handy print method
-fn pr_native(args: Vec<Value>) -> Value {
-    let s : Vec<String> = args.iter().map(|ref v| format!("{}", v)).collect();
+fn pr_native(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s : Vec<String> = args.iter().map(|v| vm.to_string(v)).collect();
     println!("{}", s.join(" "));
-    return Value::Undefined;
+    Ok(Value::Undefined)
+}
+
+/// Runs promise jobs and timers, waiting for each timer to come due, until
+/// there is nothing left to do. A rejection nothing handled by then fails
+/// like an uncaught error.
+fn run_event_loop(vm: &mut VM) -> Result<(), ScriptError> {
+    vm.run_jobs();
+    while let Some(deadline) = vm.next_timer() {
+        vm.clock_mut().wait_until(deadline);
+        vm.run_due_timers()?;
+    }
+    match vm.take_unhandled_rejection() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Renders a diagnostic with the source it points at, looked up by file
+/// name among `sources`.
+fn render(diagnostic: &Diagnostic, sources: &[(String, String)], color: bool) -> String {
+    let file = diagnostic.span.as_ref().map(|span| &span.file[..]);
+    let source = sources.iter().find(|&(name, _)| Some(&name[..]) == file);
+    diagnostic.render(source.map_or("", |(_, source)| source), color)
+}
+
+/// Whether to color diagnostics written to `stream`.
+fn use_color<T: IsTerminal>(stream: T) -> bool {
+    stream.is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+/// Parses each of `files` and reports all their syntax errors, returning
+/// whether there were none.
+fn check(files: &[String]) -> bool {
+    let color = use_color(io::stderr());
+    let mut ok = true;
+    for filename in files {
+        let mut buf = String::new();
+        if File::open(filename).and_then(|mut f| f.read_to_string(&mut buf)).is_err() {
+            eprintln!("Failed to open {}", filename);
+            ok = false;
+            continue;
+        }
+        let sources = [(filename.clone(), buf.clone())];
+        let (_, errors) = Parser::new().parse_recovering(buf, filename);
+        for error in &errors {
+            eprintln!("{}\n", render(error, &sources, color));
+        }
+        if !errors.is_empty() {
+            let plural = if errors.len() == 1 { "" } else { "s" };
+            eprintln!("{}: {} syntax error{}", filename, errors.len(), plural);
+            ok = false;
+        }
+    }
+    ok
 }
 
 fn main() {
     let mut parser = Parser::new();
-    let mut scopes = HashMap::new();
+    let mut vm = VM::new();
 
-    add_native!("print", pr_native, scopes);
+    add_native!("print", pr_native, vm);
+
+    let mut args: Vec<String> = env::args().collect();
+
+    // `check` only parses the files given, reporting every syntax error.
+    if args.len() > 1 && args[1] == "check" {
+        process::exit(if check(&args[2..]) { 0 } else { 1 });
+    }
 
-    let args: Vec<String> = env::args().collect();
+    // `--warn` reports globals the script reads but never declares.
+    let warn = args.len() > 1 && args[1] == "--warn";
+    if warn {
+        args.remove(1);
+    }
 
     // If there are args we're running a script.
     if args.len() > 1 {
         let filename = &args[1];
-        let mut f =  match File::open(filename) {
-            Ok(f) => f,
-            Err(_) => panic!("Failed to open {}", filename)
-        };
         let mut buf = String::new();
-        f.read_to_string(&mut buf).unwrap();
-        match parser.parse_lines(buf) {
-            Err(msg) => println!("{}", msg),
+        if let Err(err) = File::open(filename).and_then(|mut f| f.read_to_string(&mut buf)) {
+            eprintln!("error: failed to read {}: {}", filename, err);
+            process::exit(1);
+        }
+        let color = use_color(io::stderr());
+        let sources = [(filename.clone(), buf.clone())];
+        match parser.parse_file(buf, filename) {
+            Err(err) => {
+                eprintln!("{}", render(&err, &sources, color));
+                process::exit(1);
+            },
             Ok(statements) => {
-                let script = compile_script(statements);
-                let mut vm = VM::new(script);
-                let result = vm.run(&mut scopes);
-                match result {
-                    Ok(_) => (),
-                    Err(msg) => panic!("Error: {}", msg)
+                let (script, warnings) =
+                    compile_script_checked(statements, filename, &vm.global_names());
+                if warn {
+                    for warning in warnings {
+                        eprintln!("{}", render(&warning.diagnostic(), &sources, color));
+                    }
+                }
+                if let Err(err) = vm.run(script).and_then(|_| run_event_loop(&mut vm)) {
+                    eprintln!("{}", render(&err.diagnostic(), &sources, color));
+                    process::exit(1);
                 }
             }
         }
@@ -64,25 +163,40 @@ fn main() {
 
     println!("v{} (github.com/mrrrgn/rust-js)", VERSION);
 
+    // Every input is kept, as functions from earlier ones can fail later.
+    let color = use_color(io::stdout());
+    let mut sources = Vec::new();
     loop {
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        match parser.parse_lines(input.clone()) {
-            Err(msg) => println!("{}", msg),
+        match io::stdin().read_line(&mut input) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("error: failed to read the input: {}", err);
+                process::exit(1);
+            },
+        }
+        let name = format!("<repl:{}>", sources.len() + 1);
+        sources.push((name.clone(), input.clone()));
+        match parser.parse_file(input, &name) {
+            Err(err) => println!("{}", render(&err, &sources, color)),
             Ok(statements) => {
-                let script = compile_script(statements);
-                let mut vm = VM::new(script);
-                let result = vm.run(&mut scopes);
+                let script = compile_script(statements, &name);
+                let result = vm.run(script);
                 match result {
                     Ok(Some(value)) => println!("{}", value),
                     Ok(None) => (),
-                    Err(msg) => println!("Error: {}", msg)
+                    Err(err) => println!("{}", render(&err.diagnostic(), &sources, color)),
+                }
+                if let Err(err) = run_event_loop(&mut vm) {
+                    println!("{}", render(&err.diagnostic(), &sources, color));
                 }
             }
         }
     }
 }
 
+#[cfg(test)]
 macro_rules! assert_ok {
     ($e: expr) => (
         match $e {
@@ -96,12 +210,10 @@ macro_rules! assert_ok {
 fn eval(code: &str) -> Value {
     let mut parser = Parser::new();
     let ast = assert_ok!(parser.parse_lines(code.to_string()));
-    let script = compile_script(ast);
+    let script = compile_script(ast, "test.js");
 
-    let mut vm = VM::new(script);
-
-    let mut scopes = HashMap::new();
-    assert_ok!(vm.run(&mut scopes)).expect("script did not produce a value")
+    let mut vm = VM::new();
+    assert_ok!(vm.run(script)).expect("script did not produce a value")
 }
 
 #[test]
@@ -131,3 +243,976 @@ fn it_works() {
     /* functions */
     assert_eq!(eval("(function (x){return x*2;})(25)"), Value::Number(50.0));
 }
+
+#[test]
+fn precedence() {
+    let cases = [
+        ("1 - 2 - 3", Value::Number(-4.0)),
+        ("12 / 3 / 2", Value::Number(2.0)),
+        ("8 - 2 * 3 - 1", Value::Number(1.0)),
+        ("2 + 3 * 4", Value::Number(14.0)),
+        ("2 * 3 + 4", Value::Number(10.0)),
+        ("(1 - 2) * 3", Value::Number(-3.0)),
+        ("1-2", Value::Number(-1.0)),
+        ("-2 * 3", Value::Number(-6.0)),
+        ("2 - -2", Value::Number(4.0)),
+        ("1 < 2 == true", Value::Bool(true)),
+        ("2 == 2 == true", Value::Bool(true)),
+        ("1 + 2 < 4", Value::Bool(true)),
+        ("3 > 2 > 1", Value::Bool(false)),
+        ("typeof 1 + \"x\"", Value::Str("numberx".to_string())),
+        ("[1, 2].length * 2", Value::Number(4.0)),
+        ("true ? 1 : 2", Value::Number(1.0)),
+        ("false ? 1 : true ? 2 : 3", Value::Number(2.0)),
+        ("1 + 1 == 2 ? \"yes\" : \"no\"", Value::Str("yes".to_string())),
+        ("(1, 2, 3)", Value::Number(3.0)),
+        ("let a = 0; let b = 0; a = b = 3; a + b", Value::Number(6.0)),
+        ("let a = 0; a = 1 + 2 * 3", Value::Number(7.0)),
+        ("let a = 1; a = a + 1, a * 10", Value::Number(20.0)),
+        ("let a = 0; true ? a = 1 : a = 2; a", Value::Number(1.0)),
+        ("let o = {}; o.a = o.b = 2; o.a + o.b", Value::Number(4.0)),
+        ("function f(a, b) { return b; }; f((1, 2), 3)", Value::Number(3.0)),
+        ("let log = []; function f(x) { log.push(x); return x; }; f(1) - f(2) * f(3); log.join()",
+         Value::Str("1,2,3".to_string())),
+    ];
+    for &(code, ref expected) in cases.iter() {
+        assert_eq!(eval(code), *expected, "{}", code);
+    }
+}
+
+#[test]
+fn unary_operators() {
+    assert_eq!(eval("let x = 5; -x"), Value::Number(-5.0));
+    assert_eq!(eval("let x = 5; - -x"), Value::Number(5.0));
+    assert_eq!(eval("-\"3\""), Value::Number(-3.0));
+    assert_eq!(eval("+\" 42 \""), Value::Number(42.0));
+    assert_eq!(eval("+\"\""), Value::Number(0.0));
+    assert_eq!(eval("+\"4x\""), Value::Number(f64::NAN));
+    assert_eq!(eval("+true + +false"), Value::Number(1.0));
+    assert_eq!(eval("+[]"), Value::Number(0.0));
+    assert_eq!(eval("+[7]"), Value::Number(7.0));
+    assert_eq!(eval("+{}"), Value::Number(f64::NAN));
+    assert_eq!(eval("let u = void 0; +u"), Value::Number(f64::NAN));
+    assert_eq!(eval("!true"), Value::Bool(false));
+    assert_eq!(eval("!0"), Value::Bool(true));
+    assert_eq!(eval("!\"\""), Value::Bool(true));
+    assert_eq!(eval("!!{}"), Value::Bool(true));
+    assert_eq!(eval("let done = false; !done == true"), Value::Bool(true));
+    assert_eq!(eval("void 5"), Value::Undefined);
+    assert_eq!(eval("let n = 0; void (n = 3); n"), Value::Number(3.0));
+    assert_eq!(eval("typeof typeof 1"), Value::Str("string".to_string()));
+    assert_eq!(eval("typeof -\"x\""), Value::Str("number".to_string()));
+    assert_eq!(eval("-2 * -3"), Value::Number(6.0));
+    assert_eq!(eval("let o = {n: 2}; -o.n"), Value::Number(-2.0));
+}
+
+#[test]
+fn logical_operators() {
+    assert_eq!(eval("1 && 2"), Value::Number(2.0));
+    assert_eq!(eval("0 && 2"), Value::Number(0.0));
+    assert_eq!(eval("\"\" || \"default\""), Value::Str("default".to_string()));
+    assert_eq!(eval("3 || 4"), Value::Number(3.0));
+    assert_eq!(eval("let u = void 0; u ?? 5"), Value::Number(5.0));
+    assert_eq!(eval("0 ?? 5"), Value::Number(0.0));
+    assert_eq!(eval("false ?? 5"), Value::Bool(false));
+    assert_eq!(eval("let a = 1; let b = 0; if (a && !b) { a = 10; }; a"), Value::Number(10.0));
+
+    // && binds tighter than ||, and both looser than comparisons
+    assert_eq!(eval("true || false && false"), Value::Bool(true));
+    assert_eq!(eval("1 < 2 && 2 < 3"), Value::Bool(true));
+    assert_eq!(eval("0 || 1 ? \"a\" : \"b\""), Value::Str("a".to_string()));
+
+    // the right side only runs when it is needed
+    assert_eq!(eval("let n = 0; function inc() { n = n + 1; return true; }; \
+                     false && inc(); true || inc(); 1 ?? inc(); true && inc(); n"),
+               Value::Number(1.0));
+    assert_eq!(eval("let o = void 0; o && o.missing"), Value::Undefined);
+
+    // ?? needs parentheses to be mixed with || or &&
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("void 0 ?? 0 || 2".to_string()).unwrap_err().message,
+               "`??` can't be mixed with `||` without parentheses");
+    assert_eq!(parser.parse_lines("1 ?? 2 && 3".to_string()).unwrap_err().message,
+               "`??` can't be mixed with `&&` without parentheses");
+    assert_eq!(parser.parse_lines("1 && 2 ?? 3".to_string()).unwrap_err().message,
+               "`??` can't be mixed with `&&` without parentheses");
+    assert_eq!(eval("(void 0 ?? 0) || 2"), Value::Number(2.0));
+    assert_eq!(eval("void 0 ?? (0 || 2)"), Value::Number(2.0));
+    assert_eq!(eval("void 0 ?? void 0 ?? 3"), Value::Number(3.0));
+}
+
+#[test]
+fn conditionals() {
+    assert_eq!(eval("let r = 0; if (1 < 2) { r = 1; } else { r = 2; }; r"), Value::Number(1.0));
+    assert_eq!(eval("let r = 0; if (1 > 2) { r = 1; } else { r = 2; }; r"), Value::Number(2.0));
+
+    // the condition runs once, and the then branch can't fall into the else
+    assert_eq!(eval("let n = 0; function check() { n = n + 1; return n == 1; }; \
+                     if (check()) { n = n + 10; } else { n = n + 100; }; n"),
+               Value::Number(11.0));
+    assert_eq!(eval("let x = true; let r = 0; if (x) { x = false; r = 1; } else { r = 2; }; r"),
+               Value::Number(1.0));
+
+    let chain = "function sign(n) { if (n < 0) { return -1; } else if (n == 0) { return 0; } \
+                 else { return 1; } }; ";
+    assert_eq!(eval(&format!("{}sign(-5)", chain)), Value::Number(-1.0));
+    assert_eq!(eval(&format!("{}sign(0)", chain)), Value::Number(0.0));
+    assert_eq!(eval(&format!("{}sign(5)", chain)), Value::Number(1.0));
+    assert_eq!(eval("let r = 0; if (false) { r = 1; } else if (false) { r = 2; }; r"), Value::Number(0.0));
+}
+
+#[test]
+fn loops() {
+    assert_eq!(eval("let total = 0; for (let i = 0; i < 5; i = i + 1) { total = total + i; }; total"),
+               Value::Number(10.0));
+    assert_eq!(eval("let i = 0; for (; i < 3;) { i = i + 1; }; i"), Value::Number(3.0));
+    assert_eq!(eval("let n = 0; for (;;) { n = n + 1; if (n == 4) { break; } }; n"), Value::Number(4.0));
+    assert_eq!(eval("let n = 0; do { n = n + 1; } while (n < 3); n"), Value::Number(3.0));
+    assert_eq!(eval("let n = 10; do { n = n + 1; } while (false); n"), Value::Number(11.0));
+
+    // continue goes to the update in a for loop and the condition otherwise
+    assert_eq!(eval("let odd = []; for (let i = 0; i < 6; i = i + 1) { \
+                       if (i == 2 || i == 4) { continue; } odd.push(i); }; odd.join()"),
+               Value::Str("0,1,3,5".to_string()));
+    assert_eq!(eval("let i = 0; let n = 0; while (i < 5) { i = i + 1; if (i == 3) { continue; } n = n + i; }; n"),
+               Value::Number(12.0));
+    assert_eq!(eval("let i = 0; let n = 0; do { i = i + 1; if (i < 3) { continue; } n = n + 1; } while (i < 5); n"),
+               Value::Number(3.0));
+
+    // labeled jumps leave or restart an outer loop
+    assert_eq!(eval("let pairs = []; outer: for (let i = 0; i < 3; i = i + 1) { \
+                       for (let j = 0; j < 3; j = j + 1) { \
+                         if (j == 2) { continue outer; } if (i == 2) { break outer; } \
+                         pairs.push(i * 10 + j); } }; pairs.join()"),
+               Value::Str("0,1,10,11".to_string()));
+    assert_eq!(eval("let r = 0; block: if (true) { r = 1; break block; r = 2; }; r"), Value::Number(1.0));
+
+    // locals and captured variables in the loop body are popped on the way out
+    assert_eq!(eval("function f() { let fs = []; for (let i = 0; i < 3; i = i + 1) { let k = i * 10; \
+                       fs.push(function () { return k; }); if (i == 1) { break; } } \
+                       let after = 7; return fs[0]() + fs[1]() + after; }; f()"),
+               Value::Number(17.0));
+
+    // every iteration gets its own copy of a declared loop variable
+    assert_eq!(eval("let fs = []; for (let i = 0; i < 3; i = i + 1) { fs.push(function () { return i; }); }; \
+                     fs.map(function (f) { return f(); }).join()"),
+               Value::Str("0,1,2".to_string()));
+    assert_eq!(eval("function f() { let fs = []; for (let i = 0; i < 6; i = i + 1) { \
+                       let g = function () { i = i + 1; return i; }; fs.push(g); \
+                       if (i == 1) { g(); continue; } if (i == 4) { break; } } \
+                       return fs.map(function (f) { return f(); }).join(); }; f()"),
+               Value::Str("1,3,4,5".to_string()));
+
+    // finally blocks run when a jump leaves them
+    assert_eq!(eval("let log = []; for (let i = 0; i < 3; i = i + 1) { \
+                       try { if (i == 1) { continue; } if (i == 2) { break; } log.push(i); } \
+                       finally { log.push(i + 10); } }; log.join()"),
+               Value::Str("0,10,11,12".to_string()));
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("break".to_string()).unwrap_err().message, "illegal break statement");
+    assert_eq!(parser.parse_lines("while (true) { function f() { continue; } }".to_string()).unwrap_err().message,
+               "illegal continue statement");
+    assert_eq!(parser.parse_lines("for (;;) { break nowhere; }".to_string()).unwrap_err().message,
+               "undefined label 'nowhere'");
+    assert_eq!(parser.parse_lines("a: if (true) { continue a; }".to_string()).unwrap_err().message,
+               "label 'a' is not on a loop");
+}
+
+#[test]
+fn for_each_loops() {
+    assert_eq!(eval("let total = 0; for (let x of [1, 2, 3]) { total = total + x; }; total"), Value::Number(6.0));
+    assert_eq!(eval("let s = \"\"; for (const c of \"hello\") { s = c + s; }; s"),
+               Value::Str("olleh".to_string()));
+    assert_eq!(eval("let keys = []; for (let k in {a: 1, b: 2, c: 3}) { keys.push(k); }; keys.join()"),
+               Value::Str("a,b,c".to_string()));
+    assert_eq!(eval("let keys = \"\"; for (let i in [5, 6]) { keys = keys + i; }; keys"),
+               Value::Str("01".to_string()));
+    assert_eq!(eval("let x = 0; for (x of [4, 5]) {}; x"), Value::Number(5.0));
+
+    // elements pushed while looping are visited
+    assert_eq!(eval("let a = [1]; let n = 0; for (let x of a) { n = n + 1; if (x < 3) { a.push(x + 1); } }; n"),
+               Value::Number(3.0));
+
+    // objects are iterated through their @@iterator method
+    assert_eq!(eval("let range = {\"@@iterator\": function () { let i = 0; \
+                       return {next: function () { i = i + 1; return {value: i, done: i > 4}; }}; }}; \
+                     let total = 0; for (let x of range) { if (x == 2) { continue; } total = total + x; }; total"),
+               Value::Number(8.0));
+
+    // every iteration gets its own binding
+    assert_eq!(eval("let fs = []; for (let x of [1, 2, 3]) { fs.push(function () { return x; }); \
+                       if (x == 2) { break; } }; fs[0]() + fs[1]()"),
+               Value::Number(3.0));
+    assert_eq!(eval("function f() { for (let x of [1, 2, 3]) { if (x == 2) { return x * 10; } } }; f()"),
+               Value::Number(20.0));
+    assert_eq!(eval("let s = \"\"; outer: for (let i of [1, 2]) { for (let k in {a: 1, b: 2}) { s = `${s}${i}${k}`; \
+                       continue outer; } }; s"),
+               Value::Str("1a2a".to_string()));
+
+    // leaving a loop early closes its iterator, running a generator's finally
+    let g = "let log = []; function* g() { try { yield 1; yield 2; yield 3; } finally { log.push(\"closed\"); } }; ";
+    let cases = [
+        ("for (let x of g()) { log.push(x); if (x == 2) { break; } }", "1,2,closed"),
+        ("for (let x of g()) { log.push(x); }", "1,2,3,closed"),
+        ("for (let x of g()) { if (x == 1) { continue; } log.push(x); }", "2,3,closed"),
+        ("function f() { for (let x of g()) { return x; } }; log.push(f())", "closed,1"),
+        ("try { for (let x of g()) { throw x; } } catch (e) { log.push(e); }", "closed,1"),
+        ("outer: for (let i of [1, 2]) { for (let x of g()) { log.push(i); continue outer; } }", "1,closed,2,closed"),
+        ("try { for (let x of g()) { try { log.push(x); } finally { break; } } } finally { log.push(\"done\"); }",
+         "1,closed,done"),
+    ];
+    for &(code, expected) in cases.iter() {
+        let code = format!("{}{}; log.join()", g, code);
+        assert_eq!(eval(&code), Value::Str(expected.to_string()), "{}", code);
+    }
+    // an error from closing doesn't replace the one leaving the loop
+    assert_eq!(eval("let it = {next: function () { return {value: 1, done: false}; }, \
+                       \"return\": function () { throw \"from return\"; }}; \
+                     let iterable = {\"@@iterator\": function () { return it; }}; \
+                     let r = []; try { for (let x of iterable) { throw \"from body\"; } } catch (e) { r.push(e); }; \
+                     try { for (let x of iterable) { break; } } catch (e) { r.push(e); }; r.join()"),
+               Value::Str("from body,from return".to_string()));
+
+    assert_eq!(eval("let r = 0; try { for (let x of 5) {} } catch (e) { r = e.message; }; r"),
+               Value::Str("number is not iterable".to_string()));
+    assert_eq!(eval("let r = 0; try { for (let x of {}) {} } catch (e) { r = e.message; }; r"),
+               Value::Str("object is not iterable".to_string()));
+}
+
+#[test]
+fn strings() {
+    assert_eq!(eval(r#""a\tb\n\\\"\'\x41\u0042\u{43}""#), Value::Str("a\tb\n\\\"'ABC".to_string()));
+    assert_eq!(eval(r#"'\u{1F600} h\u00e9llo'"#), Value::Str("\u{1F600} h\u{e9}llo".to_string()));
+    assert_eq!(eval("\"line \\\ncontinued\""), Value::Str("line continued".to_string()));
+
+    assert_eq!(eval("let name = \"world\"; `hello ${name}!`"), Value::Str("hello world!".to_string()));
+    assert_eq!(eval("`${1 + 2}${true}` + `\\``"), Value::Str("3true`".to_string()));
+    assert_eq!(eval("`one\ntwo`"), Value::Str("one\ntwo".to_string()));
+    assert_eq!(eval("let o = {a: {b: 2}}; `${o.a.b} ${ {c: 3}.c } ${`in${`ner`}`}`"),
+               Value::Str("2 3 inner".to_string()));
+    assert_eq!(eval("function f(x) { return `<${x}>`; }; f([1, 2]) + f(\"$\") + `$ {}`"),
+               Value::Str("<1,2><$>$ {}".to_string()));
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("let s = \"abc;\n".to_string()).unwrap_err().to_string(),
+               "unterminated string literal at 1:9");
+    parser = Parser::new();
+    assert_eq!(parser.parse_lines("`abc ${1 + 2}".to_string()).unwrap_err().to_string(),
+               "unterminated template literal at 1:13");
+    parser = Parser::new();
+    assert_eq!(parser.parse_lines("1;\n`one\ntwo".to_string()).unwrap_err().to_string(),
+               "unterminated template literal at 2:1");
+    parser = Parser::new();
+    assert_eq!(parser.parse_lines("\"\\u{110000}\"".to_string()).unwrap_err().to_string(),
+               "invalid unicode escape at 1:1");
+}
+
+#[test]
+fn numbers() {
+    let cases = [
+        ("3000000000", 3000000000.0),
+        ("9007199254740993", 9007199254740992.0),
+        ("0xFF + 0Xa", 265.0),
+        ("0o17 + 0b101", 20.0),
+        ("0x1_0000_0000", 4294967296.0),
+        ("0x200000000000011", 144115188075855904.0),
+        ("0x20000000000001", 9007199254740992.0),
+        ("0x20000000000003", 9007199254740996.0),
+        ("0b1_0000000000_0000000000_0000000000_0000000000_0000000000_001_0000000000_1", 18446744073709555712.0),
+        ("0o1777777777777777777777", 18446744073709551616.0),
+        ("1e3 + 2.5E-1 + .5e+1", 1005.25),
+        ("1_000_000.000_1", 1000000.0001),
+        ("0.1 + 0.2", 0.1 + 0.2),
+        ("0 + 0.5", 0.5),
+        ("1e400", f64::INFINITY),
+        ("let o = {0x10: 1, 1e2: 2}; o[16] + o[100]", 3.0),
+    ];
+    for &(code, expected) in cases.iter() {
+        assert_eq!(eval(code), Value::Number(expected), "{}", code);
+    }
+    assert_eq!(eval("0x200000000000011 == 144115188075855904"), Value::Bool(true));
+    assert_eq!(eval(&format!("0x{}", "f".repeat(256))), Value::Number(f64::INFINITY));
+
+    let errors = [
+        ("1 + 0x", "missing digits in numeric literal at 1:5"),
+        ("let a =\n  2e+;", "missing digits in numeric literal at 2:3"),
+        ("1__000", "numeric separators are only allowed between digits at 1:1"),
+        ("100_", "numeric separators are only allowed between digits at 1:1"),
+        ("0x_1", "numeric separators are only allowed between digits at 1:1"),
+        ("017", "leading zeros are not allowed at 1:1"),
+        ("0b102", "invalid digit in numeric literal at 1:1"),
+        ("0o8", "invalid digit in numeric literal at 1:1"),
+        ("3in", "identifier starts immediately after numeric literal at 1:1"),
+        ("0xFG", "identifier starts immediately after numeric literal at 1:1"),
+    ];
+    for &(code, message) in errors.iter() {
+        assert_eq!(Parser::new().parse_lines(code.to_string()).unwrap_err().to_string(), message, "{}", code);
+    }
+}
+
+#[test]
+fn unicode() {
+    assert_eq!(eval("let 名前 = \"世界\"; `こんにちは、${名前}！`"), Value::Str("こんにちは、世界！".to_string()));
+    assert_eq!(eval("let $x_1 = 2; let _ = 3; let café = $x_1 * _; café"), Value::Number(6.0));
+    assert_eq!(eval("let s = \"\"; for (let c of \"😀é字\") { s = c + \"|\" + s; }; s"),
+               Value::Str("字|é|😀|".to_string()));
+    assert_eq!(eval("/* ✓ */ \"🦀\" // 🦀"), Value::Str("🦀".to_string()));
+
+    // a byte order mark and non-ASCII spaces separate tokens
+    assert_eq!(eval("\u{feff}let x = 1;\u{a0}x\u{2003}+\u{feff}2"), Value::Number(3.0));
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("\u{feff}1 +\u{3000}".to_string()).unwrap_err().to_string(),
+               "expected an expression, found the end of the input at 1:3");
+    assert_eq!(parser.parse_lines("\u{feff}\u{feff}@".to_string()).unwrap_err().to_string(),
+               "unexpected character `@` at 1:2");
+
+    // columns count characters, not bytes
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("\"日本\" + 🦀".to_string()).unwrap_err().to_string(),
+               "unexpected character `🦀` at 1:8");
+    let mut lexer = Lexer::new();
+    assert_ok!(lexer.tokenize("`😀\n${名}` + \"日本\" + x".to_string()));
+    let positions: Vec<Position> = lexer.tokens().iter().map(|t| t.position()).collect();
+    assert_eq!(positions, vec!(Position{line: 1, col: 1}, Position{line: 2, col: 3}, Position{line: 2, col: 4},
+                               Position{line: 2, col: 7}, Position{line: 2, col: 9}, Position{line: 2, col: 14},
+                               Position{line: 2, col: 16}));
+}
+
+#[test]
+fn comments() {
+    assert_eq!(eval("// the answer\nlet x = 4; /* doubled,\n   on purpose */ x * 2 // done"), Value::Number(8.0));
+    assert_eq!(eval("let s = \"// not a comment\"; s"), Value::Str("// not a comment".to_string()));
+    assert_eq!(eval("`/* ${1 /* inside */ + 1} */`"), Value::Str("/* 2 */".to_string()));
+    assert_eq!(eval("8 / 2 /* / */ / 2"), Value::Number(2.0));
+
+    let mut lexer = Lexer::new();
+    assert_ok!(lexer.tokenize("// one\n/* two\n */ a /* three */ b // four".to_string()));
+    let comments = |token: usize| -> Vec<Comment> { lexer.tokens()[token].comments().to_vec() };
+    assert_eq!(comments(0), vec!(
+        Comment{text: "// one".to_string(), position: Position{line: 1, col: 1}},
+        Comment{text: "/* two\n */".to_string(), position: Position{line: 2, col: 1}},
+    ));
+    assert_eq!(lexer.tokens()[0].position(), Position{line: 3, col: 5});
+    assert_eq!(comments(1), vec!(Comment{text: "/* three */".to_string(), position: Position{line: 3, col: 7}}));
+    assert_eq!(lexer.trailing_comments().to_vec(),
+               vec!(Comment{text: "// four".to_string(), position: Position{line: 3, col: 21}}));
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("1 +\n  /* oops".to_string()).unwrap_err().to_string(),
+               "unterminated comment at 2:3");
+}
+
+#[test]
+fn generators() {
+    let count = "function* count(n) { let i = 0; while (i < n) { yield i; i = i + 1; } return \"end\"; }; ";
+    assert_eq!(eval(&format!("{}let g = count(2); [g.next().value, g.next().value, g.next().value, \
+                               g.next().done].join()", count)),
+               Value::Str("0,1,end,true".to_string()));
+    assert_eq!(eval(&format!("{}let total = 0; for (let x of count(5)) {{ total = total + x; }}; total", count)),
+               Value::Number(10.0));
+
+    // next() sends the result of the yield back in
+    assert_eq!(eval("function* echo() { let got = []; while (true) { let x = yield got.length; \
+                       if (typeof x == \"undefined\") { return got.join(); } got.push(x); } } \
+                     let g = echo(); g.next(); g.next(\"a\"); g.next(\"b\"); g.next().value"),
+               Value::Str("a,b".to_string()));
+
+    // return() runs finally blocks and throw() raises at the yield
+    assert_eq!(eval("let log = []; function* g() { try { yield 1; yield 2; } finally { log.push(\"f\"); } } \
+                     let it = g(); it.next(); let r = it.return(7); \
+                     [r.value, r.done, log.join(), it.next().done].join()"),
+               Value::Str("7,true,f,true".to_string()));
+    // operands pending around the yield don't get in the way of returning
+    let returned = |body: &str| eval(&format!(
+        "let log = []; function* g() {{ try {{ {} }} finally {{ let a = 5; log.push(a); }} }} \
+         let it = g(); it.next(); let r = it.return(7); [r.value, r.done, log.join()].join()", body));
+    assert_eq!(returned("return 1 + (yield 1);"), Value::Str("7,true,5".to_string()));
+    assert_eq!(returned("log.push(1, 2, yield 3, 4);"), Value::Str("7,true,5".to_string()));
+    assert_eq!(returned("let x = 1; return [x, \"a\" + (x + (yield x))];"), Value::Str("7,true,5".to_string()));
+    assert_eq!(eval("function* g() { while (true) { try { yield 1; } catch (e) { yield e + \"!\"; } } } \
+                     let it = g(); it.next(); it.throw(\"boom\").value"),
+               Value::Str("boom!".to_string()));
+    assert_eq!(eval("function* g() { yield 1; } let r = 0; \
+                     try { g().throw(\"early\"); } catch (e) { r = e; }; r"),
+               Value::Str("early".to_string()));
+    assert_eq!(eval("let it = 0; function* g() { it.next(); } it = g(); let r = 0; \
+                     try { it.next(); } catch (e) { r = e.message; }; r"),
+               Value::Str("generator is already running".to_string()));
+
+    // captured variables stay shared across suspensions
+    assert_eq!(eval("function* g() { let n = 0; yield function () { n = n + 10; }; yield n; } \
+                     let it = g(); let bump = it.next().value; bump(); it.next().value"),
+               Value::Number(10.0));
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_lines("function f() { yield 1; }".to_string()).unwrap_err().message,
+               "yield is only valid in generator functions");
+
+    // the host can drive a generator too
+    let mut vm = VM::new();
+    let script = compile_script(assert_ok!(parser.parse_lines(format!("{}count(2)", count))), "test.js");
+    let generator = assert_ok!(vm.run(script)).unwrap();
+    assert_eq!(assert_ok!(vm.resume_generator(&generator, Resume::Next, Value::Undefined)),
+               (Value::Number(0.0), false));
+    assert_eq!(assert_ok!(vm.resume_generator(&generator, Resume::Return, Value::Number(5.0))),
+               (Value::Number(5.0), true));
+    assert_eq!(assert_ok!(vm.resume_generator(&generator, Resume::Next, Value::Undefined)),
+               (Value::Undefined, true));
+}
+
+#[test]
+fn promises() {
+    let mut parser = Parser::new();
+    let mut vm = VM::new();
+    let mut run = |vm: &mut VM, code: &str| {
+        let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+        let result = assert_ok!(vm.run(script));
+        vm.run_jobs();
+        result
+    };
+
+    // reactions run after the script, in the order they were queued
+    run(&mut vm, "let log = []; \
+                  Promise.resolve(1).then(function (v) { log.push(v); return v + 1; }) \
+                    .then(function (v) { log.push(v); }); \
+                  Promise.reject(\"no\").then(function (v) { log.push(\"unreachable\"); }) \
+                    .catch(function (e) { log.push(e); throw \"again\"; }) \
+                    .catch(function (e) { log.push(e); }); \
+                  log.push(\"sync\")");
+    assert_eq!(run(&mut vm, "log.join()"), Some(Value::Str("sync,1,2,no,again".to_string())));
+
+    // async functions run up to their first await and return a promise
+    run(&mut vm, "let steps = []; \
+                  async function add(x) { steps.push(\"start\"); let a = await Promise.resolve(x); \
+                    let b = await (a + 1); return a + b; } \
+                  let sum = add(1); steps.push(\"after call\"); \
+                  async function fails() { try { await Promise.reject(\"bad\"); } \
+                    catch (e) { steps.push(\"caught \" + e); } throw \"worse\"; } \
+                  let failed = fails()");
+    assert_eq!(run(&mut vm, "steps.join()"), Some(Value::Str("start,after call,caught bad".to_string())));
+    let sum = run(&mut vm, "sum").unwrap();
+    assert_eq!(vm.promise_state(&sum), Some(PromiseState::Fulfilled(Value::Number(3.0))));
+    let failed = run(&mut vm, "failed").unwrap();
+    assert_eq!(vm.promise_state(&failed), Some(PromiseState::Rejected(Value::Str("worse".to_string()))));
+
+    // the host settles promises the script is waiting on
+    let pending = vm.new_promise();
+    vm.define_global("pending", pending.clone());
+    let doubled = run(&mut vm, "async function double() { return (await pending) * 2; } double()").unwrap();
+    assert_eq!(vm.promise_state(&doubled), Some(PromiseState::Pending));
+    vm.collect_garbage();
+    assert_ok!(vm.resolve_promise(&pending, Value::Number(21.0)));
+    vm.run_jobs();
+    assert_eq!(vm.promise_state(&doubled), Some(PromiseState::Fulfilled(Value::Number(42.0))));
+
+    let pending = vm.new_promise();
+    vm.define_global("pending", pending.clone());
+    let result = run(&mut vm, "async function guarded() { try { return await pending; } \
+                                 catch (e) { return \"recovered from \" + e; } } guarded()").unwrap();
+    assert_ok!(vm.reject_promise(&pending, Value::Str("timeout".to_string())));
+    vm.run_jobs();
+    assert_eq!(vm.promise_state(&result),
+               Some(PromiseState::Fulfilled(Value::Str("recovered from timeout".to_string()))));
+    assert!(vm.resolve_promise(&Value::Number(1.0), Value::Undefined).is_err());
+
+    assert_eq!(parser.parse_lines("function f() { await 1; }".to_string()).unwrap_err().message,
+               "await is only valid in async functions");
+}
+
+#[test]
+fn timers() {
+    let mut parser = Parser::new();
+    let clock = VirtualClock::new();
+    let mut vm = VM::new();
+    vm.set_clock(Box::new(clock.clone()));
+    let mut run = |vm: &mut VM, code: &str| {
+        let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+        assert_ok!(vm.run(script))
+    };
+    run(&mut vm, "let log = []; \
+                  setTimeout(function () { log.push(\"b\"); }, 20); \
+                  setTimeout(function (x) { log.push(x); \
+                    Promise.resolve(\"job\").then(function (v) { log.push(v); }); }, 10, \"a\"); \
+                  setTimeout(function () { log.push(\"c\"); }, 20); \
+                  let ticks = 0; \
+                  let id = setInterval(function () { ticks = ticks + 1; log.push(\"tick\"); \
+                    if (ticks == 3) { clearInterval(id); } }, 15); \
+                  clearTimeout(setTimeout(function () { log.push(\"cancelled\"); }, 5))");
+
+    assert_ok!(vm.run_due_timers());
+    assert_eq!(run(&mut vm, "log.join()"), Some(Value::Str("".to_string())));
+    clock.advance(10);
+    assert_ok!(vm.run_due_timers());
+    assert_eq!(run(&mut vm, "log.join()"), Some(Value::Str("a,job".to_string())));
+    clock.advance(10);
+    assert_ok!(vm.run_due_timers());
+    assert_eq!(run(&mut vm, "log.join()"), Some(Value::Str("a,job,tick,b,c".to_string())));
+
+    // the event loop waits out the remaining timers, which takes no real
+    // time on a virtual clock
+    assert_eq!(vm.next_timer(), Some(35));
+    assert_ok!(run_event_loop(&mut vm));
+    assert_eq!(run(&mut vm, "ticks"), Some(Value::Number(3.0)));
+    assert_eq!(clock.now(), 50);
+    assert_eq!(vm.next_timer(), None);
+
+    // delays too long to represent don't wait at all, even from a timer
+    run(&mut vm, "let fired = []; let every = 0; \
+                  setTimeout(function () { setTimeout(function () { fired.push(\"inner\"); }, 1 / 0); \
+                    every = setInterval(function () {}, 1e300); }, 1 / 0)");
+    let start = clock.now();
+    assert_ok!(vm.run_due_timers());
+    assert_ok!(vm.run_due_timers());
+    assert_eq!(run(&mut vm, "fired.join()"), Some(Value::Str("inner".to_string())));
+    assert_eq!(vm.next_timer(), Some(start + 1));
+    run(&mut vm, "clearInterval(every)");
+
+    // an error escaping a callback stops the loop
+    run(&mut vm, "setTimeout(function boom() { throw \"late\"; }, 1)");
+    let err = run_event_loop(&mut vm).unwrap_err();
+    assert_eq!(format!("{}", err.error), "late");
+    assert_eq!(err.trace[0].function, Some("boom".to_string()));
+
+    // so does a rejection nothing handles, once there is nothing left to run
+    run(&mut vm, "async function f() { throw 1; } f(); \
+                  let late = Promise.reject(\"late\"); \
+                  setTimeout(function () { late.catch(function () {}); }, 5)");
+    let err = run_event_loop(&mut vm).unwrap_err();
+    assert_eq!(format!("{}", err.error), "1");
+    assert_eq!(err.diagnostic().message, "Uncaught 1");
+    assert_ok!(run_event_loop(&mut vm));
+    run(&mut vm, "Promise.reject(\"no\").then(function () {}).catch(function () {})");
+    assert_ok!(run_event_loop(&mut vm));
+
+    assert_eq!(eval("let r = 0; try { setTimeout(5); } catch (e) { r = e.message; }; r"),
+               Value::Str("number is not a function".to_string()));
+}
+
+#[test]
+fn call_stack() {
+    let recurse = "function down(n) { if (n) { return down(n - 1); } else { return 7; } }; down(5000)";
+    assert_eq!(eval(recurse), Value::Number(7.0));
+
+    let mut parser = Parser::new();
+    let script = compile_script(assert_ok!(parser.parse_lines(recurse.to_string())), "test.js");
+    let mut vm = VM::new();
+    vm.set_max_call_depth(100);
+    let err = vm.run(script).unwrap_err();
+    assert_eq!(err.error, RuntimeError::range_error("Maximum call stack size exceeded"));
+    assert_eq!(err.trace.len(), 100);
+    // the recursion shows once in the trace, with how often it repeats
+    assert_eq!(err.diagnostic().notes, vec!("called from down (test.js:1:40)".to_string(),
+                                            "… called from down 97 more times".to_string(),
+                                            "called from <script> (test.js:1:77)".to_string()));
+    assert_eq!(err.to_string().lines().count(), 4);
+    let code = "function a(n) { return b(n); }; function b(n) { return a(n); }; a(1)";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    let err = vm.run(script).unwrap_err();
+    let notes = err.diagnostic().notes;
+    assert_eq!(notes.len(), 21);
+    assert_eq!(notes[20], "… 79 more frames");
+
+    // recursion through natives that call back into scripts is bounded too
+    let code = "function f(n) { return n ? [n].map(function () { return f(n - 1); }) : 0; }; \
+                let r = 0; try { f(3000); } catch (e) { r = e.name + \": \" + e.message; }; r";
+    assert_eq!(eval(code), Value::Str("RangeError: Maximum call stack size exceeded".to_string()));
+    assert_eq!(eval("function f(n) { return n ? [n].map(function () { return f(n - 1); })[0] : 7; }; f(50)"),
+               Value::Number(7.0));
+}
+
+#[test]
+fn scoping() {
+    /* arguments and locals don't leak into the caller */
+    assert_eq!(eval("let x = 1; function f(x) { x = x + 10; return x; }; f(5) + x"),
+               Value::Number(16.0));
+    assert_eq!(eval("let x = 1; if (x) { let x = 2; }; x"), Value::Number(1.0));
+    /* closures */
+    assert_eq!(eval("function counter() { let n = 0; return function () { n = n + 1; return n; }; };
+                     let c = counter(); c(); c(); c()"), Value::Number(3.0));
+    assert_eq!(eval("function f() { let a = 1; function g() { return a; }; a = 5; return g(); }; f()"),
+               Value::Number(5.0));
+    assert_eq!(eval("let i = 0; let f = 0;
+                     while (3 - i) { let j = i; if (i == 1) { f = function () { return j; }; } i = i + 1; };
+                     f()"), Value::Number(1.0));
+    assert_eq!(eval("function fact(n) { if (n) { return n * fact(n - 1); } else { return 1; } }; fact(5)"),
+               Value::Number(120.0));
+}
+
+#[test]
+fn runtime_errors() {
+    let code = "function inner(x) {\n  return x - \"a\";\n}\nfunction outer() { return inner(1); }\nouter()";
+    let mut parser = Parser::new();
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    let err = VM::new().run(script).unwrap_err();
+    assert_eq!(err.error, RuntimeError::TypeError{
+        message: "cannot apply '-' to number and string".to_string(),
+        operands: vec!("number", "string"),
+    });
+    assert_eq!(err.location(), Some(&Location{file: "test.js".to_string(), line: 2, col: 12}));
+    let names: Vec<Option<String>> = err.trace.iter().map(|f| f.function.clone()).collect();
+    assert_eq!(names, vec!(Some("inner".to_string()), Some("outer".to_string()),
+                           Some("<script>".to_string())));
+    assert_eq!(err.trace[2].location.as_ref().map(|l| l.line), Some(5));
+
+    let script = compile_script(assert_ok!(parser.parse_lines("let o = 1; o()".to_string())), "test.js");
+    let err = VM::new().run(script).unwrap_err();
+    assert_eq!(err.error.name(), "TypeError");
+    assert_eq!(format!("{}", err), "TypeError: number is not a function\n    at <script> (test.js:1:13)");
+}
+
+#[test]
+fn spans() {
+    let code = "let total = a +\n  f(1, [2]);\nif (total) { throw total.x; }";
+    let program = assert_ok!(Parser::new().parse_file(code.to_string(), "spans.js"));
+    let text = |span: &Span| &code[span.start..span.end];
+    assert_eq!(text(&program[0].span), "let total = a +\n  f(1, [2])");
+    assert_eq!(text(&program[1].span), "if (total) { throw total.x; }");
+    assert_eq!((&program[1].span.file[..], program[1].span.line, program[1].span.col), ("spans.js", 3, 1));
+    match program[0].kind {
+        StatementKind::Let{ref expr, ..} => match expr.kind {
+            ExpressionKind::BinaryOperation{ref l_expr, ref r_expr, ref pos, ..} => {
+                assert_eq!(text(&l_expr.span), "a");
+                assert_eq!(text(pos), "+");
+                assert_eq!(text(&r_expr.span), "f(1, [2])");
+                assert_eq!((r_expr.span.line, r_expr.span.col), (2, 3));
+            },
+            ref kind => panic!("{:?}", kind),
+        },
+        ref kind => panic!("{:?}", kind),
+    }
+
+    // the line table keeps the spans for runtime errors
+    let err = VM::new().run(compile_script(program, "spans.js")).unwrap_err();
+    assert_eq!(err.span().map(text), Some("a"));
+    let code = "function f(o) {\n  return o.x.y;\n}\nf({})";
+    let program = assert_ok!(Parser::new().parse_file(code.to_string(), "spans.js"));
+    let err = VM::new().run(compile_script(program, "spans.js")).unwrap_err();
+    let spans: Vec<(u32, &str)> = err.trace.iter().map(|f| f.span.as_ref().unwrap())
+        .map(|span| (span.line, &code[span.start..span.end])).collect();
+    assert_eq!(spans, vec!((2, "."), (4, "(")));
+}
+
+#[test]
+fn diagnostics() {
+    let code = "let x = 1;\nlet s = (x +\t2;";
+    let err = Parser::new().parse_file(code.to_string(), "test.js").unwrap_err();
+    assert_eq!(err.render(code, false), "error: expected `)`, found `;`\n \
+                                         --> test.js:2:15\n  \
+                                         |\n\
+                                         2 | let s = (x +\t2;\n  \
+                                         |             \t ^");
+    assert_eq!(err.render(code, true), "\x1b[1;31merror\x1b[0m\x1b[1m: expected `)`, found `;`\x1b[0m\n \
+                                        \x1b[1;34m-->\x1b[0m test.js:2:15\n  \
+                                        \x1b[1;34m|\x1b[0m\n\
+                                        \x1b[1;34m2 |\x1b[0m let s = (x +\t2;\n  \
+                                        \x1b[1;34m|\x1b[0m             \t \x1b[1;31m^\x1b[0m");
+
+    let code = "function f() {\n  return `${\"a\"} and ${\"b\"` +\n  1;\n}";
+    let err = Parser::new().parse_file(code.to_string(), "test.js").unwrap_err();
+    assert_eq!(err.to_string(), "unterminated template literal at 2:27");
+
+    let code = "for (;;) {\n  yield 1;\n}";
+    let err = Parser::new().parse_file(code.to_string(), "test.js").unwrap_err();
+    assert_eq!(err.render(code, false), "error: yield is only valid in generator functions\n \
+                                         --> test.js:2:3\n  \
+                                         |\n\
+                                         2 |   yield 1;\n  \
+                                         |   ^^^^^\n  \
+                                         = help: declare the function with `function*` to make it a generator");
+
+    // runtime errors and warnings render the same way
+    let code = "function f(o) { return o.x.y; }\nf({})";
+    let ast = assert_ok!(Parser::new().parse_file(code.to_string(), "test.js"));
+    let (script, warnings) = compile_script_checked(ast, "test.js", &[]);
+    assert!(warnings.is_empty());
+    let err = VM::new().run(script).unwrap_err();
+    assert_eq!(err.diagnostic().render(code, false),
+               "error: Uncaught TypeError: cannot read property 'y' of undefined\n \
+                --> test.js:1:27\n  \
+                |\n\
+                1 | function f(o) { return o.x.y; }\n  \
+                |                           ^\n  \
+                = note: called from <script> (test.js:2:2)");
+    let code = "let a = 1;\nprint(a, b)";
+    let ast = assert_ok!(Parser::new().parse_file(code.to_string(), "test.js"));
+    let (_, warnings) = compile_script_checked(ast, "test.js", &["print".to_string()]);
+    assert_eq!(warnings[0].diagnostic().render(code, false),
+               "warning: 'b' is never declared\n --> test.js:2:10\n  |\n2 | print(a, b)\n  |          ^");
+}
+
+#[test]
+fn error_recovery() {
+    let code = "let a = ;\nlet b = 2;\nif (b) { let c = ) ; c; }\nfunction f( { return 1; }\nlet d = b * 2;; }\nd";
+    let (program, errors) = Parser::new().parse_recovering(code.to_string(), "test.js");
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec!("expected an expression, found `;` at 1:9",
+                            "expected an expression, found `)` at 3:18",
+                            "expected `)`, found `{` at 4:13",
+                            "expected an expression, found `}` at 5:17"));
+    let kinds: Vec<&StatementKind> = program.iter().map(|s| &s.kind).collect();
+    match &kinds[..] {
+        [StatementKind::Let{..}, StatementKind::If{body, ..}, StatementKind::Let{..}, StatementKind::Expr(_)] =>
+            assert_eq!(body.len(), 1),
+        kinds => panic!("{:?}", kinds),
+    }
+    let err = Parser::new().parse_file(code.to_string(), "test.js").unwrap_err();
+    assert_eq!(err.to_string(), errors[0]);
+    // a lexer error ends the parse
+    let (program, errors) = Parser::new().parse_recovering("let a = ;\nlet b = #;".to_string(), "test.js");
+    assert!(program.is_empty());
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "unexpected character `#` at 2:9");
+
+    assert_eq!(eval(";let x = 1;; ;x"), Value::Number(1.0));
+
+    // input that ends in the middle of an expression is an error, not a hang
+    let at_end = |code: &str| {
+        let (_, errors) = Parser::new().parse_recovering(code.to_string(), "test.js");
+        errors.iter().map(|e| e.message.clone()).collect::<Vec<String>>().join("; ")
+    };
+    assert_eq!(at_end("print(1"), "expected `)`, found the end of the input");
+    assert_eq!(at_end("let a = 1;\nf(1, 2"), "expected `)`, found the end of the input");
+    assert_eq!(at_end("let a = 1 +"), "expected an expression, found the end of the input");
+    assert_eq!(at_end("typeof -"), "expected an expression, found the end of the input");
+    assert_eq!(at_end("function f(a"), "expected `)`, found the end of the input");
+    assert_eq!(at_end("let a = [1, 2"), "expected `]`, found the end of the input");
+    assert_eq!(at_end("let a = [1, [2]];\nlet b = [a"), "expected `]`, found the end of the input");
+    assert_eq!(at_end("let o = {a: 1,"), "expected `}`, found the end of the input");
+    assert_eq!(at_end("for ("), "expected a loop header, found the end of the input");
+    assert_eq!(at_end("for (let x of"), "expected an expression, found the end of the input");
+}
+
+#[test]
+fn blocks() {
+    assert_eq!(eval("let r = 0; if (1) { let o = {f: function () { return {a: 2}; }}; r = o.f().a; }; r"),
+               Value::Number(2.0));
+    let depth = 50;
+    let code = format!("let n = 0; {} n = n + 1; {} n", "if (1) {".repeat(depth), "}".repeat(depth));
+    assert_eq!(eval(&code), Value::Number(1.0));
+
+    // nesting is limited rather than left to overflow the stack
+    let nested = |open: &str, close: &str, n: usize| format!("let x = 1; {}x{};", open.repeat(n), close.repeat(n));
+    assert_ok!(Parser::new().parse_lines(nested("(", ")", 120)));
+    assert_ok!(Parser::new().parse_lines(nested("if (x) { ", "}", 120)));
+    for code in [nested("(", ")", 5000), nested("if (x) { ", "}", 5000), nested("[-", "]", 5000)] {
+        let (_, errors) = Parser::new().parse_recovering(code, "test.js");
+        let errors: Vec<(String, Vec<String>)> = errors.into_iter().map(|e| (e.message, e.notes)).collect();
+        assert_eq!(errors, vec!(("nesting too deep".to_string(),
+                                 vec!("statements and expressions can nest at most 128 levels deep".to_string()))));
+    }
+
+    let err = Parser::new().parse_lines("function f() {\n  if (1) { 2; }".to_string()).unwrap_err();
+    assert_eq!(err.to_string(), "expected `}`, found the end of the input at 2:15");
+    // an error inside a block doesn't take the block's closing brace with it
+    let code = "if (1) { let o = (2; }\nlet z = ;";
+    let (program, errors) = Parser::new().parse_recovering(code.to_string(), "test.js");
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec!("expected `)`, found `;` at 1:20", "expected an expression, found `;` at 2:9"));
+    assert_eq!(program.len(), 1);
+}
+
+/// Shows parse time growing linearly with input size, on deeply nested and
+/// on long flat scripts. Run with
+/// `cargo test --release parse_benchmark -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn parse_benchmark() {
+    use std::time::Instant;
+
+    // Many blocks nested `n` deep, staying under the nesting limit.
+    fn nested(n: usize) -> String {
+        format!("{}x = x + 1;{}\n", "if (x) {".repeat(n), "}".repeat(n)).repeat(500)
+    }
+    fn flat(n: usize) -> String {
+        "function f(a) { if (a) { return a * 2; } else { return [a, {b: a}]; } }\n".repeat(n)
+    }
+    for (name, make, base) in [("nested", nested as fn(usize) -> String, 15), ("flat", flat, 5000)] {
+        let mut per_token = Vec::new();
+        for scale in [1, 2, 4, 8] {
+            let code = make(base * scale);
+            let mut lexer = Lexer::new();
+            assert_ok!(lexer.tokenize(code.clone()));
+            let tokens = lexer.tokens().len();
+            let start = Instant::now();
+            assert_ok!(Parser::new().parse_lines(code));
+            let elapsed = start.elapsed();
+            println!("{:>6} x{}: {:>8} tokens in {:?}", name, scale, tokens, elapsed);
+            per_token.push(elapsed.as_secs_f64() / tokens as f64);
+        }
+        // Allow for noise, but not for the time per token growing with size.
+        assert!(per_token[3] < per_token[0] * 3.0, "{} parsing is not linear: {:?}", name, per_token);
+    }
+}
+
+#[test]
+fn undefined_names() {
+    let mut parser = Parser::new();
+    let script = compile_script(assert_ok!(parser.parse_lines("1 + missing".to_string())), "test.js");
+    let err = VM::new().run(script).unwrap_err();
+    assert_eq!(err.error, RuntimeError::ReferenceError{name: "missing".to_string()});
+    assert_eq!(format!("{}", err.error), "ReferenceError: missing is not defined");
+    assert_eq!(err.location().map(|l| l.col), Some(5));
+
+    assert_eq!(eval("typeof missing"), Value::Str("undefined".to_string()));
+    assert_eq!(eval("typeof missing == \"undefined\""), Value::Bool(true));
+    assert_eq!(eval("let f = function () {}; typeof f"), Value::Str("function".to_string()));
+    assert_eq!(eval("typeof {}"), Value::Str("object".to_string()));
+    assert_eq!(eval("function f(x) { return typeof x; }; f(1)"), Value::Str("number".to_string()));
+
+    let code = "let a = 1;\nfunction f(b) { c = b; return a + b + d; }\nprint(c, e)";
+    let ast = assert_ok!(parser.parse_lines(code.to_string()));
+    let (_, warnings) = compile_script_checked(ast, "test.js", &["print".to_string()]);
+    let names: Vec<&str> = warnings.iter().map(|w| &w.name[..]).collect();
+    assert_eq!(names, vec!("d", "e"));
+    assert_eq!(format!("{}", warnings[0]), "test.js:2:39: warning: 'd' is never declared");
+}
+
+#[test]
+fn exceptions() {
+    assert_eq!(eval("let r = 0; try { throw 5; r = 1; } catch (e) { r = e + 1; }; r"), Value::Number(6.0));
+    assert_eq!(eval("let r = 0; try { r = 1 - \"a\"; } catch (e) { r = e.name + \": \" + e.message; }; r"),
+               Value::Str("TypeError: cannot apply '-' to number and string".to_string()));
+    assert_eq!(eval("let r = 0; try { missing; } catch (e) { r = e.name; }; r"),
+               Value::Str("ReferenceError".to_string()));
+    assert_eq!(eval("function g() { throw \"deep\"; }; function f() { g(); return 1; }; \
+                     let r = 0; try { f(); } catch (e) { r = e; }; r"),
+               Value::Str("deep".to_string()));
+    assert_eq!(eval("let r = 0; try { [1, 2].map(function (x) { throw x * 10; }); } catch (e) { r = e; }; r"),
+               Value::Number(10.0));
+    assert_eq!(eval("function f() { let a = 1; try { let b = 2; throw b; } catch (e) { return a + e; } }; f()"),
+               Value::Number(3.0));
+
+    // finally runs on the way out however the try block is left
+    assert_eq!(eval("let log = []; try { log.push(1); } finally { log.push(2); }; log.join()"),
+               Value::Str("1,2".to_string()));
+    assert_eq!(eval("let log = []; try { try { throw 1; } finally { log.push(\"f\"); } } \
+                     catch (e) { log.push(e); }; log.join()"),
+               Value::Str("f,1".to_string()));
+    assert_eq!(eval("let log = []; function f() { try { return 1; } finally { log.push(2); } }; \
+                     log.push(f()); log.join()"),
+               Value::Str("2,1".to_string()));
+    assert_eq!(eval("let log = []; try { throw 1; } catch (e) { log.push(e); } finally { log.push(2); }; \
+                     log.join()"),
+               Value::Str("1,2".to_string()));
+    assert_eq!(eval("let r = 0; try { try { throw 1; } catch (e) { throw e + 1; } finally { r = 10; } } \
+                     catch (e) { r = r + e; }; r"),
+               Value::Number(12.0));
+    assert_eq!(eval("function f() { try { return 1; } finally { throw 2; } }; \
+                     let r = 0; try { f(); } catch (e) { r = e; }; r"),
+               Value::Number(2.0));
+    assert_eq!(eval("function f() { try { missing; try { return 1; } finally { } } catch (e) { return 2; } }; \
+                     f()"),
+               Value::Number(2.0));
+
+    let mut parser = Parser::new();
+    let code = "throw {name: \"Oops\", message: \"it broke\"}";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    let err = VM::new().run(script).unwrap_err();
+    assert_eq!(format!("{}", err.error), "Oops: it broke");
+    assert_eq!(err.location().map(|l| l.col), Some(1));
+}
+
+#[test]
+fn garbage_collection() {
+    let mut parser = Parser::new();
+    let mut vm = VM::new();
+    let builtins = vm.heap_stats().live_objects;
+    let code = "let keep = function () { return 1; };
+                let i = 0;
+                while (1000 - i) { let f = function () { return i; }; i = i + 1; };
+                keep()";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    vm.set_gc_threshold(4096);
+    assert_eq!(assert_ok!(vm.run(script)), Some(Value::Number(1.0)));
+
+    let stats = vm.heap_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.live_bytes < 4096 * 2);
+
+    /* only `keep` survives a full collection once the script is done */
+    vm.collect_garbage();
+    assert_eq!(vm.heap_stats().live_objects, builtins + 1);
+
+    /* a suspended generator keeps its frame's values alive */
+    let code = "function* g() { let o = {n: 1}; let f = function () { return o.n; }; \
+                  while (true) { yield f() + [o.n].length; o = {n: o.n + 1}; } } \
+                let it = g(); let total = 0; \
+                for (let i = 0; i < 500; i = i + 1) { let junk = [i, {}]; total = total + it.next().value; }; total";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    vm.set_gc_threshold(1024);
+    assert_eq!(assert_ok!(vm.run(script)), Some(Value::Number(125750.0)));
+
+    /* so do elements a filter keeps after its callback let go of them */
+    let code = "let a = [{v: 1}]; \
+                let r = a.filter(function (x) { x = 0; a.length = 0; \
+                  for (let i = 0; i < 100; i = i + 1) { let junk = [{}, {}]; } return true; }); \
+                r[0].v";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    let mut fresh = VM::new();
+    fresh.set_gc_threshold(256);
+    assert_eq!(assert_ok!(fresh.run(script)), Some(Value::Number(1.0)));
+
+    /* growing an object counts towards the next collection */
+    let code = "let a = []; for (let i = 0; i < 200000; i = i + 1) { a.push(i); }; a.length";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    let mut fresh = VM::new();
+    assert_eq!(assert_ok!(fresh.run(script)), Some(Value::Number(200000.0)));
+    let stats = fresh.heap_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.live_bytes > 200000 * std::mem::size_of::<Value>());
+
+    /* values being sorted stay alive while the comparator runs */
+    let code = "let a = []; for (let i = 0; i < 20; i = i + 1) { a.push({n: i}); }; \
+                a.sort(function (x, y) { a.length = 0; let junk = [{}, {}, [1, 2]]; return y.n - x.n; }); \
+                a.map(function (x) { return x.n; }).join()";
+    let script = compile_script(assert_ok!(parser.parse_lines(code.to_string())), "test.js");
+    vm.set_gc_threshold(256);
+    let sorted: Vec<String> = (0..20).rev().map(|n| n.to_string()).collect();
+    assert_eq!(assert_ok!(vm.run(script)), Some(Value::Str(sorted.join(","))));
+}
+
+#[test]
+fn objects() {
+    assert_eq!(eval("let o = {a: 1, \"b\": 2}; o.a + o[\"b\"]"), Value::Number(3.0));
+    assert_eq!(eval("let o = {}; o.x = 5; o[\"y\"] = o.x * 2; o.y"), Value::Number(10.0));
+    assert_eq!(eval("let o = {1: \"one\"}; o[1]"), Value::Str("one".to_string()));
+    assert_eq!(eval("let o = {inner: {v: 4}}; o.inner.v = o.inner.v + 1; o.inner.v"),
+               Value::Number(5.0));
+    assert_eq!(eval("let o = {f: function (x) { return x + 1; }}; o.f(1)"), Value::Number(2.0));
+    assert_eq!(eval("let o = {a: 1}; o.missing"), Value::Undefined);
+    assert_eq!(eval("function make() { return {n: 3}; }; make().n"), Value::Number(3.0));
+    assert_eq!(eval("let o = {return: 1, if: 2}; o.return + o.if"), Value::Number(3.0));
+    assert_eq!(Parser::new().parse_lines("let o = {`k`: 1};".to_string()).unwrap_err().message,
+               "expected a property name, found a template literal");
+}
+
+#[test]
+fn arrays() {
+    assert_eq!(eval("let a = [1, 2, 3]; a[0] + a[2]"), Value::Number(4.0));
+    assert_eq!(eval("let a = [1, 2, 3]; a.length"), Value::Number(3.0));
+    assert_eq!(eval("let a = []; a[2] = 5; a.length"), Value::Number(3.0));
+    /* arrays are dense, so absurd lengths are refused rather than allocated */
+    assert_eq!(eval("let a = []; let r = 0; try { a[1e9] = 1; } catch (e) { r = e.name; }; [r, a.length].join()"),
+               Value::Str("RangeError,0".to_string()));
+    assert_eq!(eval("let a = [1]; let r = 0; try { a.length = 4294967295; } catch (e) { r = e.message; }; \
+                     [r, a.length].join()"),
+               Value::Str("invalid array length,1".to_string()));
+    assert_eq!(eval("let a = [1]; a.push(2, 3); a.pop() + a.length"), Value::Number(5.0));
+    assert_eq!(eval("[1, 2, 3, 4].slice(1, -1).join(\"-\")"), Value::Str("2-3".to_string()));
+    assert_eq!(eval("[1, 2, 3].map(function (x) { return x * 2; }).join()"),
+               Value::Str("2,4,6".to_string()));
+    assert_eq!(eval("[1, 2, 3, 4].filter(function (x) { return x > 2; }).length"),
+               Value::Number(2.0));
+    assert_eq!(eval("[1, 2, 3, 4].reduce(function (acc, x) { return acc + x; })"),
+               Value::Number(10.0));
+    assert_eq!(eval("[1, 2].reduce(function (acc, x) { return acc + x; }, 10)"),
+               Value::Number(13.0));
+    assert_eq!(eval("let total = 0; [1, 2, 3].forEach(function (x) { total = total + x; }); total"),
+               Value::Number(6.0));
+    assert_eq!(eval("[\"a\", \"b\"].indexOf(\"b\") + [\"a\"].indexOf(\"z\")"), Value::Number(0.0));
+    assert_eq!(eval("[10, 9, 1].sort().join()"), Value::Str("1,10,9".to_string()));
+    assert_eq!(eval("[10, 9, 1].sort(function (a, b) { return a - b; }).join()"),
+               Value::Str("1,9,10".to_string()));
+    assert_eq!(eval("let o = {items: [[1], [2, 3]]}; o.items[1][0]"), Value::Number(2.0));
+}
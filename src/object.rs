@@ -1,11 +1,20 @@
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::collections::HashMap;
 
+use heap::Gc;
+use lexer::Span;
 use opcode::OpCode;
 use value::Value;
+use vm::VM;
+use error::RuntimeError;
 
+/// A function implemented in Rust. It gets the VM it runs on, the `this`
+/// value and the arguments it was called with.
 #[derive(Clone)]
 pub enum Native {
-    Function(fn(Vec<Value>) -> Value)
+    Function(fn(&mut VM, Value, Vec<Value>) -> Result<Value, RuntimeError>)
 }
 
 impl fmt::Debug for Native {
@@ -14,19 +23,242 @@ impl fmt::Debug for Native {
     }
 }
 
+/// Where a closure finds one of its captured variables when it is created:
+/// either a local slot of the enclosing function, or one of the enclosing
+/// function's own upvalues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub index:      usize,
+    pub is_local:   bool,
+}
+
+/// A range of instructions whose errors are handled by jumping to `target`,
+/// with the stack cut back to `depth` slots above the frame base and the
+/// error value pushed.
 #[derive(Debug, Clone)]
+pub struct Handler {
+    pub start:      usize,
+    pub end:        usize,
+    pub target:     usize,
+    pub depth:      usize,
+}
+
+/// What calling a function does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionKind {
+    Normal,
+    /// Returns a generator that runs the body as it is resumed.
+    Generator,
+    /// Runs the body up to its first `await` and returns a promise of its
+    /// result.
+    Async,
+}
+
+/// A compiled script function. Function bodies are shared between every value
+/// referring to them, so calling or copying a function never copies its code.
+#[derive(Debug)]
+pub struct Function {
+    pub name:       Option<String>,
+    pub arity:      usize,
+    pub captures:   Vec<Capture>,
+    pub body:       Vec<OpCode>,
+    pub file:       Rc<String>,
+    pub lines:      Vec<Span>,      // the source of each instruction
+    pub handlers:   Vec<Handler>,   // innermost first
+    pub kind:       FunctionKind,
+}
+
+/// A captured variable. It points into the VM stack while the variable's
+/// scope is alive and holds the value itself once that scope has exited.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A function together with the variables it captured from the scopes it
+/// was defined in.
+#[derive(Debug)]
+pub struct Closure {
+    pub function:   Rc<Function>,
+    pub upvalues:   Vec<Gc>,
+}
+
+/// Steps through the elements of an array, or the characters of a string,
+/// for `for...of`.
+#[derive(Debug)]
+pub struct BuiltinIterator {
+    pub source:     Value,
+    pub next:       usize,  // an element index, or a byte offset into a string
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratorState {
+    /// Called, but its body hasn't started running.
+    Created,
+    Suspended,
+    Running,
+    Done,
+}
+
+/// The frame of a generator function call, kept off the VM stack while the
+/// generator isn't running.
+#[derive(Debug)]
+pub struct Generator {
+    pub closure:    Gc,
+    pub function:   Rc<Function>,
+    pub ip:         usize,
+    pub stack:      Vec<Value>,         // the frame's slots, from local zero up
+    pub upvalues:   Vec<(Gc, usize)>,   // captured slots, closed while suspended
+    pub state:      GeneratorState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromiseState {
+    Pending,
+    Fulfilled(Value),
+    Rejected(Value),
+}
+
+/// What to do once a promise settles.
+#[derive(Debug, Clone)]
+pub enum Reaction {
+    /// Callbacks given to `then`, whose result settles the promise `then`
+    /// returned. A missing callback passes the outcome straight through.
+    Then{on_fulfilled: Value, on_rejected: Value, derived: Gc},
+    /// An `await`, which resumes the async function and eventually settles
+    /// the promise it returned.
+    Await{generator: Gc, promise: Gc},
+}
+
+impl Reaction {
+    pub fn trace(&self, grey: &mut Vec<Gc>) {
+        match *self {
+            Reaction::Then{ref on_fulfilled, ref on_rejected, derived} => {
+                grey.extend(on_fulfilled.handle());
+                grey.extend(on_rejected.handle());
+                grey.push(derived);
+            },
+            Reaction::Await{generator, promise} => {
+                grey.push(generator);
+                grey.push(promise);
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Promise {
+    pub state:      PromiseState,
+    pub reactions:  Vec<Reaction>,  // waiting for the promise to settle
+}
+
+impl Promise {
+    pub fn new(state: PromiseState) -> Promise {
+        Promise{state, reactions: Vec::new()}
+    }
+}
+
+/// The properties of an object, remembering the order keys were added in.
+#[derive(Debug, Default)]
+pub struct PropertyMap {
+    keys:   Vec<String>,
+    values: HashMap<String, Value>,
+    key_bytes: usize,   // the total length of the keys
+}
+
+impl PropertyMap {
+    pub fn new() -> PropertyMap {
+        PropertyMap::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) {
+        if !self.values.contains_key(&key) {
+            self.key_bytes += key.len();
+            self.keys.push(key.clone());
+        }
+        self.values.insert(key, value);
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub fn values(&self) -> impl Iterator<Item=&Value> {
+        self.values.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[derive(Debug)]
 pub enum Object {
-    Function{args: Vec<String>, body: Vec<OpCode>},
+    Closure(Closure),
     Native(Native),
+    Upvalue(Upvalue),
+    Ordinary(PropertyMap),
+    Array(Vec<Value>),
+    Iterator(BuiltinIterator),
+    Generator(Generator),
+    Promise(Promise),
 }
 
-/// A helper for adding native functions to some scope, where the scope should
-/// be some global object.
+impl Object {
+    /// Pushes every heap object this one refers to.
+    pub fn trace(&self, grey: &mut Vec<Gc>) {
+        match *self {
+            Object::Closure(ref c) => grey.extend(c.upvalues.iter().cloned()),
+            Object::Upvalue(Upvalue::Closed(ref v)) => grey.extend(v.handle()),
+            Object::Ordinary(ref props) => grey.extend(props.values().filter_map(Value::handle)),
+            Object::Array(ref elements) => grey.extend(elements.iter().filter_map(Value::handle)),
+            Object::Iterator(ref it) => grey.extend(it.source.handle()),
+            Object::Generator(ref g) => {
+                grey.push(g.closure);
+                grey.extend(g.stack.iter().filter_map(Value::handle));
+                grey.extend(g.upvalues.iter().map(|&(upvalue, _)| upvalue));
+            },
+            Object::Promise(ref p) => {
+                if let PromiseState::Fulfilled(ref v) | PromiseState::Rejected(ref v) = p.state {
+                    grey.extend(v.handle());
+                }
+                for reaction in &p.reactions {
+                    reaction.trace(grey);
+                }
+            },
+            Object::Upvalue(Upvalue::Open(_)) | Object::Native(_) => (),
+        }
+    }
+
+    /// Memory owned by the object beyond its own slot on the heap.
+    pub fn extra_size(&self) -> usize {
+        match *self {
+            Object::Closure(ref c) => c.upvalues.capacity() * mem::size_of::<Gc>(),
+            Object::Array(ref elements) => elements.capacity() * mem::size_of::<Value>(),
+            Object::Generator(ref g) => g.stack.capacity() * mem::size_of::<Value>(),
+            Object::Ordinary(ref props) => {
+                props.key_bytes * 2 + props.len() * (mem::size_of::<String>() * 2 + mem::size_of::<Value>())
+            },
+            _ => 0,
+        }
+    }
+}
+
+/// A helper for adding native functions to the global scope of some VM, or
+/// to the properties of some object on its heap.
 #[macro_export]
 macro_rules! add_native {
-    ($name: expr, $func: ident, $scope: ident) => {
-        $scope.insert($name.to_string(),
-                      Value::Object(Object::Native(Native::Function($func))));
+    ($name: expr, $func: ident, $vm: ident) => {
+        let native = $vm.alloc(Object::Native(Native::Function($func)));
+        $vm.define_global($name, native);
+    };
+    ($name: expr, $func: ident, $vm: ident, $props: ident) => {
+        let native = $vm.alloc(Object::Native(Native::Function($func)));
+        $props.set($name.to_string(), native);
     };
 }
-
@@ -1,3 +1,6 @@
+use std::rc::Rc;
+
+use object::Function;
 use value::Value;
 
 #[derive(Debug, Clone)]
@@ -15,9 +18,37 @@ pub enum OpCode {
     GtEq,   // >=
     Def,    // scopes[stack.pop()] = stack.pop()
     Ret,
-    Call,   // stack.pop()(...)
+    Call(usize),      // callee(...) with a `this` and n args on the stack
+    CallMethod(usize), // object[key](...) with n args on the stack
     JumpIfNot(i32),   // if !stack.pop() -> pc += jump
     JumpIf(i32),      // if stack.pop() -> pc += jump
     Jump(i32),        // pc += jump
+    JumpIfDefined(i32), // if stack.pop() is not nullish -> pc += jump
     GetName(String),  // stack.push(scopes[String])
+    GetLocal(usize),  // stack.push(frame[usize])
+    SetLocal(usize),  // frame[usize] = stack.pop()
+    GetUpvalue(usize), // stack.push(closure.upvalues[usize])
+    SetUpvalue(usize), // closure.upvalues[usize] = stack.pop()
+    CloseUpvalue,     // move a captured stack.pop() into its upvalue
+    Closure(Rc<Function>), // stack.push(closure over the current frame)
+    Pop,              // stack.pop()
+    Dup,              // stack.push(stack.last())
+    NewObject(usize), // stack.push({stack.pop(): stack.pop(), ...}) with n properties
+    GetProp,          // stack.push(stack.pop()[stack.pop()])
+    SetProp,          // stack.push(stack.pop()[stack.pop()] = stack.pop())
+    NewArray(usize),  // stack.push([stack.pop(), ...]) with n elements
+    GetIterator,      // stack.push(iterator over stack.pop())
+    GetKeys,          // stack.push(iterator over the own keys of stack.pop())
+    IterNext(i32),    // stack.push(stack.last().next()), or pc += jump once done
+    IterClose,        // stack.pop().return(), if it has one
+    Neg,              // stack.push(-stack.pop())
+    Plus,             // stack.push(+stack.pop())
+    Not,              // stack.push(!stack.pop())
+    TypeOf,           // stack.push(typeof stack.pop())
+    ToStr,            // stack.push(String(stack.pop()))
+    Void,             // stack.pop(); stack.push(undefined)
+    TypeOfName(String), // stack.push(typeof scopes[String]), undefined if missing
+    Throw,            // raise stack.pop() to the nearest handler
+    Yield(i32, usize), // suspend the generator with stack.pop(); pc += jump to return
+                      // with only the given number of locals on the stack
 }
@@ -1,285 +1,870 @@
+use std::mem;
+
 use lexer::*;
 use value::*;
 use ast::*;
+use object::FunctionKind;
+use diagnostic::Diagnostic;
+
+/// How deeply statements and expressions may nest. The parser recurses for
+/// each level, so this keeps pathological input from overflowing the stack.
+const MAX_NESTING: usize = 128;
+
+// Binding powers of the operators, loosest first, following the JavaScript
+// precedence table. An operand of an operator is parsed at a binding power
+// above the operator's own if it associates left, and at the same one if it
+// associates right.
+const COMMA: u8 = 1;
+const ASSIGNMENT: u8 = 2;
+const CONDITIONAL: u8 = 3;
+const LOGICAL_OR: u8 = 4;
+const LOGICAL_AND: u8 = 5;
+const EQUALITY: u8 = 9;
+const RELATIONAL: u8 = 10;
+const ADDITIVE: u8 = 12;
+const MULTIPLICATIVE: u8 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+/// Every infix operator with its binding power and associativity.
+const OPERATORS: &[(&str, u8, Assoc)] = &[
+    (",",   COMMA,          Assoc::Left),
+    ("=",   ASSIGNMENT,     Assoc::Right),
+    ("?",   CONDITIONAL,    Assoc::Right),
+    ("??",  LOGICAL_OR,     Assoc::Left),
+    ("||",  LOGICAL_OR,     Assoc::Left),
+    ("&&",  LOGICAL_AND,    Assoc::Left),
+    ("==",  EQUALITY,       Assoc::Left),
+    ("!=",  EQUALITY,       Assoc::Left),
+    ("<",   RELATIONAL,     Assoc::Left),
+    ("<=",  RELATIONAL,     Assoc::Left),
+    (">",   RELATIONAL,     Assoc::Left),
+    (">=",  RELATIONAL,     Assoc::Left),
+    ("+",   ADDITIVE,       Assoc::Left),
+    ("-",   ADDITIVE,       Assoc::Left),
+    ("*",   MULTIPLICATIVE, Assoc::Left),
+    ("/",   MULTIPLICATIVE, Assoc::Left),
+];
+
+/// The binding power and associativity of `token` as an infix operator.
+fn infix_operator(token: &Token) -> Option<(u8, Assoc)> {
+    match *token.token_type() {
+        TokenType::Comma | TokenType::Equals | TokenType::Question | TokenType::BinOp => {
+            OPERATORS.iter()
+                .find(|&&(op, _, _)| op == token.value())
+                .map(|&(_, power, assoc)| (power, assoc))
+        },
+        _ => None,
+    }
+}
+
+/// Fails if the `??` operator is being mixed with `||` or `&&`, which needs
+/// parentheses to say which goes first. `inner` is the operator of an
+/// operand of `outer`.
+fn check_nullish_mix(inner: &BinOp, outer: &Token) -> Result<(), Diagnostic> {
+    let outer_op = match Lexer::bin_op(outer.value()) {
+        Some(op) => op,
+        None => return Ok(()),
+    };
+    let nullish = |op: &BinOp| matches!(*op, BinOp::Nullish);
+    if !outer_op.is_logical() || nullish(inner) == nullish(&outer_op) {
+        return Ok(());
+    }
+    let other = if nullish(inner) { outer_op } else { inner.clone() };
+    let other = if matches!(other, BinOp::And) { "&&" } else { "||" };
+    Err(Diagnostic::error(&format!("`??` can't be mixed with `{}` without parentheses", other), outer.span().clone())
+        .with_help("wrap one side in parentheses"))
+}
+
+/// A statement `break` or `continue` can leave: a loop, or any labeled
+/// statement for a labeled `break`.
+#[derive(Debug, Clone)]
+struct JumpTarget {
+    label:      Option<String>,
+    is_loop:    bool,
+}
 
 pub struct Parser {
-    lexer:  Lexer
+    lexer:  Lexer,
+    // The statements enclosing the one being parsed, innermost last.
+    targets: Vec<JumpTarget>,
+    // The kind of the innermost function being parsed, which decides
+    // whether `yield` and `await` are allowed.
+    function_kind: FunctionKind,
+    // The syntax errors found so far.
+    diagnostics: Vec<Diagnostic>,
+    // How many statements and operands enclose the cursor.
+    nesting: usize,
 }
 
 impl Parser {
     pub fn new() -> Parser {
-        return Parser{lexer: Lexer::new()};
+        Parser{
+            lexer: Lexer::new(),
+            targets: Vec::new(),
+            function_kind: FunctionKind::Normal,
+            diagnostics: Vec::new(),
+            nesting: 0,
+        }
     }
 
-    pub fn from(tokens: Vec<Token>) -> Parser {
-        return Parser{lexer: Lexer::from(tokens)};
+    /// The span of the current token.
+    fn span(&self) -> Span {
+        self.lexer.curr_token().span().clone()
     }
 
-    fn parse_term(&mut self) -> Result<Expression, String> {
-        match *self.lexer.curr_type() {
-            TokenType::Int => {
-                let int = self.lexer.curr_value().parse::<i32>().unwrap();
-                return Ok(Expression::Atom(Value::Number(int as f64)));
-            },
-            TokenType::Float => {
-                let float = self.lexer.curr_value().parse::<f64>().unwrap();
-                return Ok(Expression::Atom(Value::Number(float)));
-            },
-            TokenType::Str => {
-                return Ok(Expression::Atom(Value::Str(self.lexer.curr_value())));
-            },
-            TokenType::True => {
-                return Ok(Expression::Atom(Value::Bool(true)));
-            },
-            TokenType::False => {
-                return Ok(Expression::Atom(Value::Bool(false)));
+    /// An error about the current token.
+    fn error(&self, message: &str) -> Diagnostic {
+        Diagnostic::error(message, self.span())
+    }
+
+    /// An expression spanning from `start` through the current token.
+    fn expression(&self, kind: ExpressionKind, start: &Span) -> Expression {
+        Expression::new(kind, start.to(self.lexer.curr_token().span()))
+    }
+
+    /// A statement spanning from `start` through the current token.
+    fn statement(&self, kind: StatementKind, start: &Span) -> Statement {
+        Statement::new(kind, start.to(self.lexer.curr_token().span()))
+    }
+
+    /// Whether the cursor is on the `close` token ending a list, failing if
+    /// the input ends first.
+    fn at_list_end(&self, close: TokenType) -> Result<bool, Diagnostic> {
+        if self.lexer.tokens_remaining() < 1 {
+            return Err(self.lexer.unexpected(close.describe()));
+        }
+        Ok(self.lexer.current_is_type(close))
+    }
+
+    /// Goes one level deeper into the source, failing if that is too deep.
+    /// Every `nest` is paired with a `self.nesting -= 1` on the way out.
+    fn nest(&mut self) -> Result<(), Diagnostic> {
+        if self.nesting >= MAX_NESTING {
+            return Err(self.error("nesting too deep")
+                .with_note(&format!("statements and expressions can nest at most {} levels deep", MAX_NESTING)));
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    /// Parses an operand, counting it as a level of nesting.
+    fn parse_unary(&mut self) -> Result<Expression, Diagnostic> {
+        self.nest()?;
+        let e = self.parse_prefix();
+        self.nesting -= 1;
+        e
+    }
+
+    /// Parses a prefix operator applied to an operand, or just the operand.
+    fn parse_prefix(&mut self) -> Result<Expression, Diagnostic> {
+        // At the end of the input the current token is the last one again,
+        // which could be read as another operator forever.
+        if self.lexer.tokens_remaining() < 1 {
+            return Err(self.lexer.unexpected("an expression"));
+        }
+        let start = self.span();
+        let op = match *self.lexer.curr_type() {
+            TokenType::Await => return self.parse_await(),
+            TokenType::TypeOf => UnaryOp::TypeOf,
+            TokenType::Void => UnaryOp::Void,
+            TokenType::Not => UnaryOp::Not,
+            TokenType::BinOp if self.lexer.curr_value() == "-" => UnaryOp::Neg,
+            TokenType::BinOp if self.lexer.curr_value() == "+" => UnaryOp::Plus,
+            _ => {
+                let e = self.parse_primary()?;
+                return self.parse_postfix(e);
             },
-            TokenType::Identifier => {
-                let e1 = Expression::GetName(self.lexer.curr_value());
-                if self.lexer.next_token() {
-                    match *self.lexer.curr_type() {
-                        TokenType::BinOp => return self.parse_binop(e1),
-                        TokenType::LPar  => return self.parse_call(e1),
-                        _ => {
-                            self.lexer.prev_token();
-                            return Ok(e1)
-                        }
+        };
+        self.lexer.next_token();
+        let expr = self.parse_unary()?;
+        Ok(self.expression(ExpressionKind::UnaryOperation{op, expr: Box::new(expr)}, &start))
+    }
+
+    fn parse_await(&mut self) -> Result<Expression, Diagnostic> {
+        if self.function_kind != FunctionKind::Async {
+            return Err(self.error("await is only valid in async functions")
+                .with_help("declare the function with `async function` to use `await` in it"));
+        }
+        let start = self.span();
+        self.lexer.next_token();
+        let value = self.parse_unary()?;
+        let kind = ExpressionKind::Await{value: Box::new(value), pos: start.clone()};
+        Ok(self.expression(kind, &start))
+    }
+
+    /// Parses any calls and property accesses following an expression.
+    fn parse_postfix(&mut self, mut e: Expression) -> Result<Expression, Diagnostic> {
+        loop {
+            match self.lexer.peek_type() {
+                Some(&TokenType::LPar) => {
+                    self.lexer.next_token();
+                    e = self.parse_call(e)?;
+                },
+                Some(&TokenType::Period) => {
+                    self.lexer.next_token();
+                    let pos = self.span();
+                    self.lexer.next_token();
+                    if !self.at_property_name() {
+                        self.lexer.match_token(TokenType::Identifier)?;
                     }
-                }
-                return Ok(e1);
-            },
-            TokenType::LPar => {
-                self.lexer.next_token();
-                let e = try!(self.parse_expression());
+                    let property = Expression::new(ExpressionKind::Atom(Value::Str(self.lexer.curr_value())),
+                                                   self.span());
+                    let start = e.span.clone();
+                    let kind = ExpressionKind::Member{object: Box::new(e), property: Box::new(property), pos};
+                    e = self.expression(kind, &start);
+                },
+                Some(&TokenType::LBracket) => {
+                    self.lexer.next_token();
+                    let pos = self.span();
+                    self.lexer.next_token();
+                    let property = self.parse_expression()?;
+                    self.lexer.next_token();
+                    self.lexer.match_token(TokenType::RBracket)?;
+                    let start = e.span.clone();
+                    let kind = ExpressionKind::Member{object: Box::new(e), property: Box::new(property), pos};
+                    e = self.expression(kind, &start);
+                },
+                _ => return Ok(e),
+            }
+        }
+    }
+
+    /// Whether the cursor is on a word that can name a property, which
+    /// reserved words like `return` can as well as identifiers.
+    fn at_property_name(&self) -> bool {
+        matches!(*self.lexer.curr_type(),
+                 TokenType::Identifier | TokenType::Let | TokenType::Const | TokenType::In |
+                 TokenType::Function | TokenType::Return | TokenType::If | TokenType::Else |
+                 TokenType::While | TokenType::For | TokenType::Do | TokenType::Break |
+                 TokenType::Continue | TokenType::True | TokenType::False | TokenType::TypeOf |
+                 TokenType::Void | TokenType::Throw | TokenType::Try | TokenType::Catch |
+                 TokenType::Finally | TokenType::Yield | TokenType::Async | TokenType::Await)
+    }
+
+    fn parse_object(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.span();
+        let mut properties = Vec::new();
+        self.lexer.next_token();
+        while !self.at_list_end(TokenType::RCBrace)? {
+            let key = match *self.lexer.curr_type() {
+                TokenType::Str => self.lexer.curr_value(),
+                TokenType::Number => Value::Number(self.lexer.curr_token().number()).to_property_key(),
+                _ if self.at_property_name() => self.lexer.curr_value(),
+                _ => return Err(self.lexer.unexpected("a property name")),
+            };
+            self.lexer.next_token();
+            self.lexer.match_token(TokenType::Colon)?;
+            self.lexer.next_token();
+            properties.push((key, self.parse_assignment()?));
+            self.lexer.next_token();
+            if self.lexer.current_is_type(TokenType::Comma) {
                 self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::RPar));
+            }
+        }
+        Ok(self.expression(ExpressionKind::Object(properties), &start))
+    }
+
+    fn parse_array(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.span();
+        let mut elements = Vec::new();
+        self.lexer.next_token();
+        while !self.at_list_end(TokenType::RBracket)? {
+            elements.push(self.parse_assignment()?);
+            self.lexer.next_token();
+            if self.lexer.current_is_type(TokenType::Comma) {
                 self.lexer.next_token();
-                if self.lexer.current_is_type(TokenType::LPar) {
-                    return self.parse_call(e);
-                }
-                self.lexer.prev_token();
-                return Ok(e);
-            },
-            TokenType::Function => {
+            }
+        }
+        Ok(self.expression(ExpressionKind::Array(elements), &start))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.span();
+        let kind = match *self.lexer.curr_type() {
+            TokenType::Number => ExpressionKind::Atom(Value::Number(self.lexer.curr_token().number())),
+            TokenType::Str => ExpressionKind::Atom(Value::Str(self.lexer.curr_value())),
+            TokenType::True => ExpressionKind::Atom(Value::Bool(true)),
+            TokenType::False => ExpressionKind::Atom(Value::Bool(false)),
+            TokenType::Identifier => ExpressionKind::GetName(self.lexer.curr_value()),
+            _ => return self.parse_compound(),
+        };
+        Ok(Expression::new(kind, start))
+    }
+
+    /// Parses a primary expression made of more than one token.
+    fn parse_compound(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.span();
+        match *self.lexer.curr_type() {
+            TokenType::TemplatePart | TokenType::TemplateEnd => self.parse_template(),
+            TokenType::LPar => {
                 self.lexer.next_token();
-                let name : Option<String>;
-                if self.lexer.current_is_type(TokenType::Identifier) {
-                    name = Some(self.lexer.curr_value());
-                    self.lexer.next_token();
-                } else {
-                    name = None;
-                }
-                try!(self.lexer.match_token(TokenType::LPar));
+                let e = self.parse_expression()?;
                 self.lexer.next_token();
-                let mut args = Vec::new();
-                while self.lexer.current_is_type(TokenType::Identifier) {
-                    args.push(self.lexer.curr_value());
-                     self.lexer.next_token();
-                     if self.lexer.current_is_type(TokenType::Comma) {
-                         self.lexer.next_token();
-                     }
-                }
-                try!(self.lexer.match_token(TokenType::RPar));
+                self.lexer.match_token(TokenType::RPar)?;
+                Ok(e)
+            },
+            TokenType::LCBrace => self.parse_object(),
+            TokenType::LBracket => self.parse_array(),
+            TokenType::Function => self.parse_function(FunctionKind::Normal, &start),
+            TokenType::Async => {
                 self.lexer.next_token();
-                let body = try!(self.parse_block());
-                return Ok(Expression::Function{name: name, args: args, body: body});
+                self.lexer.match_token(TokenType::Function)?;
+                self.parse_function(FunctionKind::Async, &start)
             },
             TokenType::Return => {
                 self.lexer.next_token();
-                let e = try!(self.parse_expression());
-                return Ok(Expression::Return(Box::new(e)));
+                let e = self.parse_expression()?;
+                Ok(self.expression(ExpressionKind::Return(Box::new(e)), &start))
             },
-            _ => Err(String::from("unrecognized expression"))
+            _ => Err(self.lexer.unexpected("an expression")),
         }
     }
 
-    fn parse_call(&mut self, e1: Expression) -> Result<Expression, String> {
+    /// Parses a template literal, whose pieces the lexer splits around each
+    /// substitution.
+    fn parse_template(&mut self) -> Result<Expression, Diagnostic> {
+        let start = self.span();
+        let mut pieces = vec!(self.lexer.curr_value());
+        let mut substitutions = Vec::new();
+        while self.lexer.current_is_type(TokenType::TemplatePart) {
+            self.lexer.next_token();
+            substitutions.push(self.parse_expression()?);
+            self.lexer.next_token();
+            if !self.lexer.current_is_type(TokenType::TemplatePart) {
+                self.lexer.match_token(TokenType::TemplateEnd)?;
+            }
+            pieces.push(self.lexer.curr_value());
+        }
+        Ok(self.expression(ExpressionKind::Template{pieces, substitutions}, &start))
+    }
+
+    /// Parses a function from its `function` keyword on, where `start` is
+    /// that keyword or the `async` before it.
+    fn parse_function(&mut self, mut kind: FunctionKind, start: &Span) -> Result<Expression, Diagnostic> {
+        self.lexer.next_token();
+        if self.lexer.current_is_type(TokenType::BinOp) && self.lexer.curr_value() == "*" {
+            if kind == FunctionKind::Async {
+                return Err(self.error("async generators are not supported"));
+            }
+            kind = FunctionKind::Generator;
+            self.lexer.next_token();
+        }
+        let name : Option<String>;
+        if self.lexer.current_is_type(TokenType::Identifier) {
+            name = Some(self.lexer.curr_value());
+            self.lexer.next_token();
+        } else {
+            name = None;
+        }
+        self.lexer.match_token(TokenType::LPar)?;
+        self.lexer.next_token();
+        let mut args = Vec::new();
+        while self.lexer.tokens_remaining() > 0 && self.lexer.current_is_type(TokenType::Identifier) {
+            args.push(self.lexer.curr_value());
+             self.lexer.next_token();
+             if self.lexer.current_is_type(TokenType::Comma) {
+                 self.lexer.next_token();
+             }
+        }
+        self.lexer.match_token(TokenType::RPar)?;
+        self.lexer.next_token();
+        // Jumps can't leave a function.
+        let targets = mem::take(&mut self.targets);
+        let function_kind = mem::replace(&mut self.function_kind, kind);
+        let body = self.parse_block();
+        self.targets = targets;
+        self.function_kind = function_kind;
+        Ok(self.expression(ExpressionKind::Function{name, args, body: body?, kind}, start))
+    }
+
+    fn parse_call(&mut self, e1: Expression) -> Result<Expression, Diagnostic> {
+        let start = e1.span.clone();
+        let pos = self.span();
         let mut expr_stack = Vec::new();
         expr_stack.push(e1);
         self.lexer.next_token();
-        while !self.lexer.current_is_type(TokenType::RPar) {
-            expr_stack.push(try!(self.parse_expression()));
+        while !self.at_list_end(TokenType::RPar)? {
+            expr_stack.push(self.parse_assignment()?);
             self.lexer.next_token();
             if self.lexer.current_is_type(TokenType::Comma) {
                 self.lexer.next_token();
             }
         }
-        return Ok(Expression::Call(expr_stack));
+        Ok(self.expression(ExpressionKind::Call(expr_stack, pos), &start))
+    }
+
+    /// Parses an expression whose operators all bind at least as tightly as
+    /// `min`, leaving the cursor on its last token.
+    fn parse_precedence(&mut self, min: u8) -> Result<Expression, Diagnostic> {
+        let mut left = if self.lexer.current_is_type(TokenType::Yield) && min <= ASSIGNMENT {
+            self.parse_yield()?
+        } else {
+            self.parse_unary()?
+        };
+        loop {
+            let (power, assoc) = match self.lexer.peek_token().and_then(infix_operator) {
+                Some((power, assoc)) if power >= min => (power, assoc),
+                _ => return Ok(left),
+            };
+            let operand_min = match assoc {
+                Assoc::Left => power + 1,
+                Assoc::Right => power,
+            };
+            if let (Some(inner), Some(op)) = (self.bare_logical(&left), self.lexer.peek_token()) {
+                check_nullish_mix(&inner, op)?;
+            }
+            self.lexer.next_token();
+            left = self.parse_infix(left, operand_min)?;
+        }
     }
 
-    fn parse_binop(&mut self, e1: Expression) -> Result<Expression, String> {
-        let mut expr_list = vec!(e1);
-        let mut op_list : Vec<(BinOp, u8)> = Vec::new();
+    /// The operator of `e` if it's a logical operation that isn't in
+    /// parentheses, where `e` ends at the current token. Parentheses aren't
+    /// kept in the tree, but the expression inside keeps its own span, so
+    /// one ending before the current token was in parentheses.
+    fn bare_logical(&self, e: &Expression) -> Option<BinOp> {
+        match e.kind {
+            ExpressionKind::BinaryOperation{ref op, ..}
+                if op.is_logical() && e.span.end == self.lexer.curr_token().span().end => Some(op.clone()),
+            _ => None,
+        }
+    }
 
-        op_list.push(Lexer::bin_op(&self.lexer.curr_value()[..]).unwrap());
+    /// Parses the right side of the infix operator at the cursor, whose
+    /// operands bind at least as tightly as `operand_min`.
+    fn parse_infix(&mut self, left: Expression, operand_min: u8) -> Result<Expression, Diagnostic> {
+        let start = left.span.clone();
+        let pos = self.span();
+        let op = self.lexer.curr_token().clone();
         self.lexer.next_token();
-        expr_list.push(try!(self.parse_term()));
-
-        let mut end = false;
-        while expr_list.len() > 1 {
-            if !end && self.lexer.next_token() {
-                if !self.lexer.current_is_type(TokenType::BinOp) {
-                    self.lexer.prev_token();
-                    end = true;
-                    continue;
+        let kind = match *op.token_type() {
+            TokenType::Comma => {
+                let right = self.parse_precedence(operand_min)?;
+                match left.kind {
+                    ExpressionKind::Sequence(mut exprs) => {
+                        exprs.push(right);
+                        ExpressionKind::Sequence(exprs)
+                    },
+                    _ => ExpressionKind::Sequence(vec!(left, right)),
                 }
+            },
+            TokenType::Equals => {
+                match left.kind {
+                    ExpressionKind::GetName(..) | ExpressionKind::Member{..} => (),
+                    _ => return Err(Diagnostic::error("invalid assignment target", left.span)),
+                }
+                let value = self.parse_precedence(operand_min)?;
+                ExpressionKind::Assign{target: Box::new(left), value: Box::new(value), pos}
+            },
+            TokenType::Question => {
+                let then = self.parse_assignment()?;
+                self.lexer.next_token();
+                self.lexer.match_token(TokenType::Colon)?;
+                self.lexer.next_token();
+                let otherwise = self.parse_assignment()?;
+                ExpressionKind::Conditional{
+                    cond: Box::new(left),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
+                }
+            },
+            _ => {
+                let right = self.parse_precedence(operand_min)?;
+                if let Some(inner) = self.bare_logical(&right) {
+                    check_nullish_mix(&inner, &op)?;
+                }
+                ExpressionKind::BinaryOperation{
+                    l_expr: Box::new(left),
+                    op: Lexer::bin_op(op.value()).unwrap(),
+                    r_expr: Box::new(right),
+                    pos,
+                }
+            },
+        };
+        Ok(self.expression(kind, &start))
+    }
 
-                let (op2, prec2) = Lexer::bin_op(&self.lexer.curr_value()[..]).unwrap();
-
-                if prec2 > op_list.last().unwrap().1 {
+    /// Parses `yield`, which binds like an assignment and may leave out its
+    /// operand.
+    fn parse_yield(&mut self) -> Result<Expression, Diagnostic> {
+        if self.function_kind != FunctionKind::Generator {
+            return Err(self.error("yield is only valid in generator functions")
+                .with_help("declare the function with `function*` to make it a generator"));
+        }
+        let pos = self.span();
+        let value = match self.lexer.peek_type() {
+            None | Some(&TokenType::RPar) | Some(&TokenType::RBracket) | Some(&TokenType::RCBrace) |
+            Some(&TokenType::Semicolon) | Some(&TokenType::Comma) | Some(&TokenType::Colon) => None,
+            _ => {
+                self.lexer.next_token();
+                Some(Box::new(self.parse_assignment()?))
+            },
+        };
+        let start = pos.clone();
+        Ok(self.expression(ExpressionKind::Yield{value, pos}, &start))
+    }
 
-                    let e1 = expr_list.pop().unwrap();
-                    let e2 = expr_list.pop().unwrap();
-                    expr_list.push(Expression::BinaryOperation{
-                        l_expr: Box::new(e1),
-                        op: op_list.pop().unwrap().0,
-                        r_expr: Box::new(e2)
-                    });
+    /// Parses an expression that may contain the comma operator.
+    fn parse_expression(&mut self) -> Result<Expression, Diagnostic> {
+        self.parse_precedence(COMMA)
+    }
 
-                    self.lexer.next_token();
-                    expr_list.push(try!(self.parse_term()));
-                    op_list.push((op2, prec2));
+    /// Parses an expression that stops at a comma, as in argument lists.
+    fn parse_assignment(&mut self) -> Result<Expression, Diagnostic> {
+        self.parse_precedence(ASSIGNMENT)
+    }
 
-                    continue;
-                }
+    fn parse_statement(&mut self) -> Result<Statement, Diagnostic> {
+        self.nest()?;
+        let start = self.span();
+        let kind = self.parse_statement_kind();
+        self.nesting -= 1;
+        Ok(self.statement(kind?, &start))
+    }
 
+    // Each kind of statement is parsed by a method of its own, which keeps
+    // the frames of this recursion small however deep blocks nest.
+    fn parse_statement_kind(&mut self) -> Result<StatementKind, Diagnostic> {
+        match *self.lexer.curr_type() {
+            TokenType::Let | TokenType::Const => self.parse_let(),
+            TokenType::If => self.parse_if(),
+            TokenType::While => self.parse_while(),
+            TokenType::Do => self.parse_do_while(),
+            TokenType::For => self.parse_for(),
+            TokenType::Break | TokenType::Continue => self.parse_jump(),
+            TokenType::Identifier if self.lexer.peek_type() == Some(&TokenType::Colon) => self.parse_labeled(),
+            TokenType::Throw => {
+                let pos = self.span();
                 self.lexer.next_token();
-                expr_list.push(try!(self.parse_term()));
-                op_list.push((op2, prec2));
-            }
-            let bop = Expression::BinaryOperation{
-                l_expr: Box::new(expr_list.pop().unwrap()),
-                op: op_list.pop().unwrap().0,
-                r_expr: Box::new(expr_list.pop().unwrap())
+                let expr = self.parse_expression()?;
+                Ok(StatementKind::Throw{expr, pos})
+            },
+            TokenType::Try => self.parse_try(),
+            _ => Ok(StatementKind::Expr(self.parse_expression()?)),
+        }
+    }
+
+    // `const` is accepted, but not enforced, as another `let`.
+    fn parse_let(&mut self) -> Result<StatementKind, Diagnostic> {
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::Identifier)?;
+        let name = self.lexer.curr_value();
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::Equals)?;
+        self.lexer.next_token();
+        let e = self.parse_assignment()?;
+        Ok(StatementKind::Let{name, expr: e})
+    }
+
+    fn parse_if(&mut self) -> Result<StatementKind, Diagnostic> {
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::LPar)?;
+        self.lexer.next_token();
+        let cond = self.parse_expression()?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::RPar)?;
+        self.lexer.next_token();
+        let body = self.parse_block()?;
+        self.lexer.next_token();
+        if self.lexer.current_is_type(TokenType::Else) {
+            self.lexer.next_token();
+            let else_body = if self.lexer.current_is_type(TokenType::If) {
+                vec!(self.parse_statement()?)
+            } else {
+                self.parse_block()?
             };
-            expr_list.push(bop);
+            return Ok(StatementKind::IfElse{
+                cond,
+                body,
+                else_body
+            });
         }
-        return Ok(expr_list.pop().unwrap());
+        self.lexer.prev_token();
+        Ok(StatementKind::If{cond, body})
+    }
+
+    fn parse_while(&mut self) -> Result<StatementKind, Diagnostic> {
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::LPar)?;
+        self.lexer.next_token();
+        let cond = self.parse_expression()?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::RPar)?;
+        self.lexer.next_token();
+        let body = self.parse_loop_body()?;
+        Ok(StatementKind::While{cond, body})
+    }
+
+    fn parse_do_while(&mut self) -> Result<StatementKind, Diagnostic> {
+        self.lexer.next_token();
+        let body = self.parse_loop_body()?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::While)?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::LPar)?;
+        self.lexer.next_token();
+        let cond = self.parse_expression()?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::RPar)?;
+        Ok(StatementKind::DoWhile{body, cond})
     }
 
-    fn parse_expression(&mut self) -> Result<Expression, String> {
-        let e1 = try!(self.parse_term());
+    fn parse_for(&mut self) -> Result<StatementKind, Diagnostic> {
         self.lexer.next_token();
-        if self.lexer.tokens_remaining() > 0 &&
-           self.lexer.current_is_type(TokenType::BinOp) {
-            return self.parse_binop(e1);
+        self.lexer.match_token(TokenType::LPar)?;
+        self.lexer.next_token();
+        if let Some(each) = self.parse_for_each()? {
+            return Ok(each);
         }
-        if self.lexer.tokens_remaining() > 0 {
-            self.lexer.prev_token();
+        let mut init = None;
+        if !self.lexer.current_is_type(TokenType::Semicolon) {
+            init = Some(Box::new(self.parse_statement()?));
+            self.lexer.next_token();
+            self.lexer.match_token(TokenType::Semicolon)?;
         }
-        return Ok(e1);
+        self.lexer.next_token();
+        let mut cond = None;
+        if !self.lexer.current_is_type(TokenType::Semicolon) {
+            cond = Some(self.parse_expression()?);
+            self.lexer.next_token();
+            self.lexer.match_token(TokenType::Semicolon)?;
+        }
+        self.lexer.next_token();
+        let mut update = None;
+        if !self.lexer.current_is_type(TokenType::RPar) {
+            update = Some(self.parse_expression()?);
+            self.lexer.next_token();
+            self.lexer.match_token(TokenType::RPar)?;
+        }
+        self.lexer.next_token();
+        let body = self.parse_loop_body()?;
+        Ok(StatementKind::For{init, cond, update, body})
     }
 
-    fn parse_statement(&mut self) -> Result<Statement, String> {
-        match *self.lexer.curr_type() {
-            TokenType::Let => {
-                self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::Identifier));
-                let name = self.lexer.curr_value();
-                self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::Equals));
-                self.lexer.next_token();
-                let e = try!(self.parse_expression());
-                return Ok(Statement::Assignment{name: name, expr: e});
-            },
-            TokenType::Identifier => {
-                let name = self.lexer.curr_value();
-                self.lexer.next_token();
-                if !self.lexer.current_is_type(TokenType::Equals) {
-                    // Whoops, this isn't an assignment
-                    self.lexer.prev_token();
-                    let e = try!(self.parse_expression());
-                    return Ok(Statement::Expr(e));
-                }
-                self.lexer.next_token();
-                let e = try!(self.parse_expression());
-                return Ok(Statement::Assignment{name: name, expr: e});
-            },
-            TokenType::If => {
-                self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::LPar));
-                self.lexer.next_token();
-                let cond = try!(self.parse_expression());
-                self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::RPar));
-                self.lexer.next_token();
-                let body = try!(self.parse_block());
-                self.lexer.next_token();
-                if self.lexer.current_is_type(TokenType::Else) {
-                    self.lexer.next_token();
-                    let else_body = try!(self.parse_block());
-                    return Ok(Statement::IfElse{
-                        cond: cond,
-                        body: body,
-                        else_body: else_body
-                    });
-                }
-                return Ok(Statement::If{cond: cond, body: body});
+    /// Parses `break` or `continue`, checking that it has somewhere to go.
+    fn parse_jump(&mut self) -> Result<StatementKind, Diagnostic> {
+        let is_break = self.lexer.current_is_type(TokenType::Break);
+        let mut label = None;
+        if self.lexer.peek_type() == Some(&TokenType::Identifier) {
+            self.lexer.next_token();
+            label = Some(self.lexer.curr_value());
+        }
+        let target = self.targets.iter().rev().find(|t| match label {
+            Some(ref label) => t.label.as_ref() == Some(label),
+            None => t.is_loop,
+        });
+        match (target, label) {
+            (Some(t), Some(ref label)) if !is_break && !t.is_loop => {
+                Err(self.error(&format!("label '{}' is not on a loop", label)))
             },
-            TokenType::While => {
-                self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::LPar));
+            (Some(_), label) => Ok(if is_break {
+                StatementKind::Break(label)
+            } else {
+                StatementKind::Continue(label)
+            }),
+            (None, Some(label)) => Err(self.error(&format!("undefined label '{}'", label))),
+            (None, None) if is_break => Err(self.error("illegal break statement")
+                .with_note("`break` can only leave a loop or a labeled statement")),
+            (None, None) => Err(self.error("illegal continue statement")
+                .with_note("`continue` can only be used in a loop")),
+        }
+    }
+
+    fn parse_labeled(&mut self) -> Result<StatementKind, Diagnostic> {
+        let label = self.lexer.curr_value();
+        self.lexer.next_token();
+        self.lexer.next_token();
+        let is_loop = self.is_loop_ahead();
+        self.targets.push(JumpTarget{label: Some(label.clone()), is_loop});
+        let body = self.parse_statement();
+        self.targets.pop();
+        Ok(StatementKind::Labeled{label, body: Box::new(body?)})
+    }
+
+    fn parse_try(&mut self) -> Result<StatementKind, Diagnostic> {
+        self.lexer.next_token();
+        let body = self.parse_block()?;
+        let mut catch_name = None;
+        let mut catch_body = None;
+        let mut finally = None;
+        self.lexer.next_token();
+        if self.lexer.current_is_type(TokenType::Catch) {
+            self.lexer.next_token();
+            if self.lexer.current_is_type(TokenType::LPar) {
                 self.lexer.next_token();
-                let cond = try!(self.parse_expression());
+                self.lexer.match_token(TokenType::Identifier)?;
+                catch_name = Some(self.lexer.curr_value());
                 self.lexer.next_token();
-                try!(self.lexer.match_token(TokenType::RPar));
+                self.lexer.match_token(TokenType::RPar)?;
                 self.lexer.next_token();
-                let body = try!(self.parse_block());
-                return Ok(Statement::While{cond: cond, body: body});
-            },
-            _ => {
-                let e = try!(self.parse_expression());
-                return Ok(Statement::Expr(e));
-            },
+            }
+            catch_body = Some(self.parse_block()?);
+            self.lexer.next_token();
+        }
+        if self.lexer.current_is_type(TokenType::Finally) {
+            self.lexer.next_token();
+            finally = Some(self.parse_block()?);
+        } else if catch_body.is_none() {
+            return Err(self.lexer.unexpected("`catch` or `finally`"));
+        } else {
+            self.lexer.prev_token();
         }
+        Ok(StatementKind::Try{body, catch_name, catch_body, finally})
     }
 
-    fn parse_block(&mut self) -> Result<Vec<Statement>, String> {
-        try!(self.lexer.match_token(TokenType::LCBrace));
-        let mut block_tokens = Vec::new();
-        let mut brace_count = 1;
-        while self.lexer.next_token() {
-            if self.lexer.current_is_type(TokenType::LCBrace) {
-                brace_count += 1;
-            }
-            if self.lexer.current_is_type(TokenType::RCBrace) {
-                brace_count -= 1;
-            }
-            if brace_count < 1 {
-                break;
+    /// Parses the rest of a `for...of` or `for...in` loop if the cursor is
+    /// on the start of one, just after the opening parenthesis.
+    fn parse_for_each(&mut self) -> Result<Option<StatementKind>, Diagnostic> {
+        let (declare, name, kind) = {
+            let tokens = &self.lexer.tokens()[self.lexer.position()..];
+            let first = match tokens.first() {
+                Some(token) => token,
+                None => return Err(self.lexer.unexpected("a loop header")),
+            };
+            let declare = matches!(*first.token_type(), TokenType::Let | TokenType::Const);
+            let tokens = if declare { &tokens[1..] } else { tokens };
+            if tokens.len() < 2 || *tokens[0].token_type() != TokenType::Identifier {
+                return Ok(None);
             }
-            block_tokens.push(self.lexer.curr_token().clone());
+            let kind = match (tokens[1].token_type(), tokens[1].value()) {
+                (&TokenType::In, _) => TokenType::In,
+                (&TokenType::Identifier, "of") => TokenType::Identifier,
+                _ => return Ok(None),
+            };
+            (declare, tokens[0].value().to_string(), kind)
+        };
+        if declare {
+            self.lexer.next_token();
         }
-        try!(self.lexer.match_token(TokenType::RCBrace));
-        return Parser::parse_from_tokens(block_tokens);
+        self.lexer.next_token();
+        self.lexer.next_token();
+        let source = self.parse_expression()?;
+        self.lexer.next_token();
+        self.lexer.match_token(TokenType::RPar)?;
+        self.lexer.next_token();
+        let body = self.parse_loop_body()?;
+        Ok(Some(match kind {
+            TokenType::In => StatementKind::ForIn{name, declare, object: source, body},
+            _ => StatementKind::ForOf{name, declare, iterable: source, body},
+        }))
     }
 
-    fn parse_from_tokens(tokens: Vec<Token>) -> Result<Vec<Statement>, String> {
-        let mut block_parser = Parser::from(tokens);
-        return block_parser.parse_program();
+    /// Whether the statement starting at the cursor is a loop, possibly
+    /// after more labels.
+    fn is_loop_ahead(&self) -> bool {
+        let tokens = &self.lexer.tokens()[self.lexer.position()..];
+        let mut i = 0;
+        while i + 1 < tokens.len() && *tokens[i].token_type() == TokenType::Identifier &&
+              *tokens[i + 1].token_type() == TokenType::Colon {
+            i += 2;
+        }
+        tokens.get(i).is_some_and(|t| matches!(*t.token_type(),
+            TokenType::While | TokenType::Do | TokenType::For))
     }
 
-    pub fn parse_program(&mut self) -> Result<Vec<Statement>, String> {
-        let mut program = Vec::new();
-        if self.lexer.tokens().len() > 0 {
-            loop {
-                if self.lexer.tokens_remaining() < 1 {
-                    break;
-                }
-                program.push(try!(self.parse_statement()));
+    fn parse_loop_body(&mut self) -> Result<Vec<Statement>, Diagnostic> {
+        self.targets.push(JumpTarget{label: None, is_loop: true});
+        let body = self.parse_block();
+        self.targets.pop();
+        body
+    }
+
+    /// Parses a `{ ... }` block, leaving the cursor on its closing brace.
+    fn parse_block(&mut self) -> Result<Vec<Statement>, Diagnostic> {
+        self.lexer.match_token(TokenType::LCBrace)?;
+        self.lexer.next_token();
+        let body = self.parse_statements(true);
+        self.lexer.match_token(TokenType::RCBrace)?;
+        Ok(body)
+    }
+
+    /// Parses statements up to the end of the tokens, or of the block when
+    /// `in_block`. A statement with a syntax error is left out and the error
+    /// recorded, so that the rest still gets checked.
+    fn parse_statements(&mut self, in_block: bool) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        loop {
+            // Skip empty statements.
+            while self.lexer.tokens_remaining() > 0 && self.lexer.current_is_type(TokenType::Semicolon) {
                 self.lexer.next_token();
-                if self.lexer.current_is_type(TokenType::Semicolon) {
+            }
+            if self.lexer.tokens_remaining() < 1 || (in_block && self.lexer.current_is_type(TokenType::RCBrace)) {
+                break;
+            }
+            let start = self.lexer.position();
+            match self.parse_statement() {
+                Ok(statement) => {
+                    statements.push(statement);
                     self.lexer.next_token();
-                }
+                },
+                Err(error) => {
+                    self.diagnostics.push(error);
+                    self.synchronize(start, in_block);
+                },
             }
         }
-        return Ok(program);
+        statements
     }
 
-    pub fn parse_lines(&mut self, text: String) -> Result<Vec<Statement>, String> {
-        try!(self.lexer.tokenize(text.clone()));
-        let program = self.parse_program();
+    /// Skips ahead after a syntax error in the statement starting at token
+    /// `start`, to where the next statement likely begins: past a `;`, or an
+    /// unmatched `}` outside blocks, or on a keyword that starts a statement.
+    /// Inside a block an unmatched `}` is left to close it.
+    fn synchronize(&mut self, start: usize, in_block: bool) {
+        let mut depth = 0;
+        while self.lexer.tokens_remaining() > 0 {
+            match *self.lexer.curr_type() {
+                TokenType::LCBrace => depth += 1,
+                TokenType::RCBrace if depth > 0 => depth -= 1,
+                TokenType::RCBrace if in_block => return,
+                TokenType::RCBrace | TokenType::Semicolon if depth == 0 => {
+                    self.lexer.next_token();
+                    return;
+                },
+                TokenType::Let | TokenType::Const | TokenType::If | TokenType::While | TokenType::For |
+                TokenType::Do | TokenType::Function | TokenType::Return | TokenType::Throw | TokenType::Try |
+                TokenType::Break | TokenType::Continue
+                    if depth == 0 && self.lexer.position() != start => return,
+                _ => (),
+            }
+            self.lexer.next_token();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn parse_lines(&mut self, text: String) -> Result<Vec<Statement>, Diagnostic> {
+        self.parse_file(text, "<input>")
+    }
+
+    /// Parses the source of `file`, which the spans in the result name,
+    /// failing with its first syntax error.
+    pub fn parse_file(&mut self, text: String, file: &str) -> Result<Vec<Statement>, Diagnostic> {
+        let (program, mut errors) = self.parse_recovering(text, file);
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors.remove(0))
+        }
+    }
+
+    /// Parses the source of `file` as far as it can, returning the
+    /// statements that parsed along with every syntax error.
+    pub fn parse_recovering(&mut self, text: String, file: &str) -> (Vec<Statement>, Vec<Diagnostic>) {
+        self.lexer = Lexer::with_file(file);
+        self.diagnostics.clear();
+        let program = match self.lexer.tokenize(text) {
+            Ok(()) => self.parse_statements(false),
+            Err(error) => {
+                self.diagnostics.push(error);
+                Vec::new()
+            },
+        };
         self.lexer.reset();
-        return program;
+        (program, mem::take(&mut self.diagnostics))
     }
 }
@@ -0,0 +1,42 @@
+// The Promise prototype and the global `Promise` object.
+use heap::Gc;
+use object::{Object, Native, PropertyMap, Promise, PromiseState};
+use value::Value;
+use vm::VM;
+use error::RuntimeError;
+
+/// Fills in the prototype object every promise looks its methods up on, and
+/// defines the `Promise` global.
+pub fn install(vm: &mut VM, prototype: Gc) {
+    let mut props = PropertyMap::new();
+    add_native!("then", promise_then, vm, props);
+    add_native!("catch", promise_catch, vm, props);
+    *vm.heap_mut().get_mut(prototype) = Object::Ordinary(props);
+
+    let mut statics = PropertyMap::new();
+    add_native!("resolve", promise_resolve, vm, statics);
+    add_native!("reject", promise_reject, vm, statics);
+    let global = vm.alloc(Object::Ordinary(statics));
+    vm.define_global("Promise", global);
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or(Value::Undefined)
+}
+
+fn promise_then(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    vm.promise_then(&this, arg(&args, 0), arg(&args, 1))
+}
+
+fn promise_catch(vm: &mut VM, this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    vm.promise_then(&this, Value::Undefined, arg(&args, 0))
+}
+
+fn promise_resolve(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(vm.promise_for(arg(&args, 0)))
+}
+
+fn promise_reject(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let state = PromiseState::Rejected(arg(&args, 0));
+    Ok(vm.alloc(Object::Promise(Promise::new(state))))
+}
@@ -0,0 +1,138 @@
+// Timers: setTimeout and friends, and the clocks they are measured against.
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use object::{Object, Native};
+use value::Value;
+use vm::VM;
+use error::RuntimeError;
+
+/// Where timers get the time from, in milliseconds since some fixed start.
+pub trait Clock {
+    fn now(&self) -> u64;
+
+    /// Blocks until the time is at least `deadline`.
+    fn wait_until(&mut self, deadline: u64);
+}
+
+/// The real time, starting from when the clock was made.
+pub struct SystemClock {
+    start:  Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock{start: Instant::now()}
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn wait_until(&mut self, deadline: u64) {
+        let now = self.now();
+        if deadline > now {
+            thread::sleep(Duration::from_millis(deadline - now));
+        }
+    }
+}
+
+/// A clock that only moves when told to, so tests can run timers without
+/// sleeping. Clones share the same time.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    now:    Rc<Cell<u64>>,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock::default()
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.set(self.now.get() + ms);
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    /// Waiting just jumps ahead to the deadline.
+    fn wait_until(&mut self, deadline: u64) {
+        if deadline > self.now.get() {
+            self.now.set(deadline);
+        }
+    }
+}
+
+/// A callback waiting for its time to come.
+pub struct Timer {
+    pub id:         u64,
+    pub deadline:   u64,
+    pub seq:        u64,            // orders timers with the same deadline
+    pub interval:   Option<u64>,    // for timers that repeat
+    pub callback:   Value,
+    pub args:       Vec<Value>,
+}
+
+/// Defines the timer functions as globals.
+pub fn install(vm: &mut VM) {
+    add_native!("setTimeout", set_timeout, vm);
+    add_native!("setInterval", set_interval, vm);
+    add_native!("clearTimeout", clear_timer, vm);
+    add_native!("clearInterval", clear_timer, vm);
+}
+
+/// The longest delay a timer can have, in milliseconds. Like browsers, longer
+/// ones don't wait at all.
+const MAX_DELAY: f64 = 2147483647.0;
+
+/// Reads a delay argument as whole milliseconds, treating anything that
+/// isn't a number from zero to `MAX_DELAY` as zero.
+fn delay(vm: &VM, args: &[Value]) -> u64 {
+    let ms = args.get(1).map_or(0.0, |v| vm.to_number(v));
+    if (0.0..=MAX_DELAY).contains(&ms) { ms as u64 } else { 0 }
+}
+
+fn add_timer(vm: &mut VM, args: Vec<Value>, repeat: bool) -> Result<Value, RuntimeError> {
+    let callback = args.first().cloned().unwrap_or(Value::Undefined);
+    if vm.type_of(&callback) != "function" {
+        let type_name = vm.type_of(&callback);
+        return Err(RuntimeError::TypeError{
+            message: format!("{} is not a function", type_name),
+            operands: vec!(type_name),
+        });
+    }
+    let delay = delay(vm, &args);
+    let extra = args.into_iter().skip(2).collect();
+    // A repeating timer always lets some time pass between runs.
+    let interval = if repeat { Some(delay.max(1)) } else { None };
+    let id = vm.add_timer(callback, delay, interval, extra);
+    Ok(Value::Number(id as f64))
+}
+
+fn set_timeout(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    add_timer(vm, args, false)
+}
+
+fn set_interval(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    add_timer(vm, args, true)
+}
+
+fn clear_timer(vm: &mut VM, _this: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    if let Some(&Value::Number(id)) = args.first() {
+        vm.clear_timer(id as u64);
+    }
+    Ok(Value::Undefined)
+}
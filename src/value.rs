@@ -1,12 +1,13 @@
 use std::fmt;
 use std::cmp::Ordering;
-use object::Object;
+use heap::Gc;
+use error::RuntimeError;
 
 #[derive(Debug, Clone)]
 pub enum Value {
     Number(f64),
     Str(String),
-    Object(Object),
+    Object(Gc),
     Bool(bool),
     Undefined
 }
@@ -22,8 +23,9 @@ impl PartialEq for Value {
                     // The two values are different... unless they're both NaN.
                     a.is_nan() && b.is_nan()
                 },
-            (&Value::Bool(ref a), &Value::Bool(ref b)) => a == b,
-            (&Value::Str(ref a), &Value::Str(ref b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (&Value::Object(a), &Value::Object(b)) => a == b,
             (&Value::Undefined, &Value::Undefined) => true,
             _ => false
         }
@@ -72,35 +74,102 @@ impl fmt::Display for Value {
 }
 
 impl Value {
-    pub fn add(&self, b: Value) -> Result<Value, String> {
-        Ok(match (self, b) {
-            (&Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-            (&Value::Str(ref a), Value::Str(ref b)) => Value::Str(a.clone() + b),
-            _ => return Err("invalid operation".to_string())
+    pub fn add(&self, b: Value) -> Result<Value, RuntimeError> {
+        Ok(match (self, &b) {
+            (&Value::Number(a), &Value::Number(b)) => Value::Number(a + b),
+            (Value::Str(a), Value::Str(b)) => Value::Str(a.clone() + b),
+            _ => return Err(self.invalid_operands("+", &b))
         })
     }
 
-    pub fn sub(&self, b: Value) -> Result<Value, String> {
-        Ok(match (self, b) {
-            (&Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            _ => return Err("invalid operation".to_string())
+    pub fn sub(&self, b: Value) -> Result<Value, RuntimeError> {
+        Ok(match (self, &b) {
+            (&Value::Number(a), &Value::Number(b)) => Value::Number(a - b),
+            _ => return Err(self.invalid_operands("-", &b))
         })
     }
 
-    pub fn mul(&self, b: Value) -> Result<Value, String> {
-        Ok(match (self, b) {
-            (&Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => return Err("invalid operation".to_string())
+    pub fn mul(&self, b: Value) -> Result<Value, RuntimeError> {
+        Ok(match (self, &b) {
+            (&Value::Number(a), &Value::Number(b)) => Value::Number(a * b),
+            _ => return Err(self.invalid_operands("*", &b))
         })
     }
 
-    pub fn div(&self, b: Value) -> Result<Value, String> {
-        Ok(match (self, b) {
-            (&Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-            _ => return Err("invalid operation".to_string())
+    pub fn div(&self, b: Value) -> Result<Value, RuntimeError> {
+        Ok(match (self, &b) {
+            (&Value::Number(a), &Value::Number(b)) => Value::Number(a / b),
+            _ => return Err(self.invalid_operands("/", &b))
         })
     }
 
+    /// The type of the value as far as it can be told without the heap.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Number(_)    => "number",
+            Value::Str(_)       => "string",
+            Value::Bool(_)      => "boolean",
+            Value::Object(_)    => "object",
+            Value::Undefined    => "undefined",
+        }
+    }
+
+    fn invalid_operands(&self, op: &str, b: &Value) -> RuntimeError {
+        RuntimeError::TypeError{
+            message: format!("cannot apply '{}' to {} and {}", op, self.type_name(), b.type_name()),
+            operands: vec!(self.type_name(), b.type_name()),
+        }
+    }
+
+    /// The heap object this value refers to, if any.
+    pub fn handle(&self) -> Option<Gc> {
+        match *self {
+            Value::Object(handle) => Some(handle),
+            _ => None,
+        }
+    }
+
+    /// Converts the value to the string used to look it up as a property.
+    pub fn to_property_key(&self) -> String {
+        match *self {
+            Value::Str(ref s) => s.clone(),
+            Value::Object(_) => "[object Object]".to_string(),
+            _ => format!("{}", self),
+        }
+    }
+
+    /// The array index this value names, if it names one.
+    pub fn to_array_index(&self) -> Option<usize> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < u32::MAX as f64 => {
+                Some(n as usize)
+            },
+            Value::Str(ref s) => match s.parse::<u32>() {
+                Ok(i) if i.to_string() == *s => Some(i as usize),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Converts a primitive to a number. Objects are converted by the VM,
+    /// which can see inside them.
+    pub fn to_number(&self) -> f64 {
+        match *self {
+            Value::Number(n)    => n,
+            Value::Bool(b)      => if b { 1.0 } else { 0.0 },
+            Value::Str(ref s)   => string_to_number(s),
+            Value::Object(_)    => f64::NAN,
+            Value::Undefined    => f64::NAN,
+        }
+    }
+
+    /// Whether `??` replaces the value. There is no null, so only
+    /// undefined is.
+    pub fn is_nullish(&self) -> bool {
+        matches!(*self, Value::Undefined)
+    }
+
     pub fn to_boolean(&self) -> bool {
         match *self {
             Value::Number(v)    => v != 0.0,
@@ -111,3 +180,19 @@ impl Value {
         }
     }
 }
+
+/// Reads a string as a number the way `Number(s)` does: surrounding
+/// whitespace is ignored, an empty string is zero and anything else that is
+/// not a decimal number is NaN.
+pub fn string_to_number(s: &str) -> f64 {
+    let s = s.trim();
+    match s {
+        "" => 0.0,
+        "Infinity" | "+Infinity" => f64::INFINITY,
+        "-Infinity" => f64::NEG_INFINITY,
+        _ if s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) => {
+            s.parse().unwrap_or(f64::NAN)
+        },
+        _ => f64::NAN,
+    }
+}
@@ -1,13 +1,84 @@
-use std::collections::HashMap;
-use object::{Object, Native};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::rc::Rc;
+use heap::{Heap, Gc};
+#[cfg(test)]
+use heap::HeapStats;
+use object::{Object, Native, Function, Closure, Upvalue, PropertyMap, BuiltinIterator};
+use object::{Generator, GeneratorState, FunctionKind, Promise, PromiseState, Reaction};
 use opcode::OpCode;
-use value::Value;
+use value::{Value, string_to_number};
+use error::{RuntimeError, ScriptError, StackFrame, Location};
+use array;
+use generator;
+use promise;
+use timer::{self, Clock, SystemClock, Timer};
+
+/// The property a script object keeps its iterator method under, standing
+/// in for `Symbol.iterator`.
+pub const ITERATOR_KEY: &str = "@@iterator";
+
+/// The deepest the call stack may grow before a call is rejected.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10000;
+
+/// The longest an array may grow. Arrays are stored densely, so without a
+/// cap a single assignment such as `a[1e9] = 1` would ask for gigabytes.
+pub const MAX_ARRAY_LENGTH: usize = 1 << 24;
+
+/// How many times natives may nest calls back into scripts. Each one runs a
+/// dispatch loop of its own on the native stack, which is far smaller than
+/// the frame stack, so this is well below `DEFAULT_MAX_CALL_DEPTH`.
+pub const MAX_NATIVE_REENTRY: usize = 100;
+
+/// An activation record for a function call.
+struct Frame {
+    closure:    Gc,
+    function:   Rc<Function>,
+    ip:         usize,  // where to resume once a callee returns
+    base:       usize,  // stack slot of local zero
+    generator:  Option<Gc>, // the generator this frame belongs to, if any
+}
+
+/// A promise reaction waiting to run, with the outcome of the promise.
+struct Job {
+    reaction:   Reaction,
+    value:      Value,
+    rejected:   bool,
+}
+
+/// How a generator is resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    /// Continue, with the value as the result of the `yield`.
+    Next,
+    /// Return the value from the `yield`, running any finally blocks.
+    Return,
+    /// Throw the value from the `yield`.
+    Throw,
+}
 
 pub struct VM {
-    program:    Vec<OpCode>,
+    frames:     Vec<Frame>,
     stack:      Vec<Value>,
-    ip:         usize,
-    running:    bool,
+    globals:    HashMap<String, Value>,
+    heap:       Heap,
+    // Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<Gc>,
+    array_prototype: Gc,
+    generator_prototype: Gc,
+    promise_prototype: Gc,
+    // Promise reactions ready to run, oldest first.
+    jobs:       VecDeque<Job>,
+    // Promises the host made and has yet to settle.
+    host_promises: Vec<Gc>,
+    // Rejected promises with nothing yet reacting to them, oldest first.
+    unhandled:  Vec<Gc>,
+    timers:     Vec<Timer>,
+    timer_count: u64,   // counts up to give timers their ids and order
+    clock:      Box<dyn Clock>,
+    max_depth:  usize,
+    // How many dispatch loops are running, one inside the other.
+    executions: usize,
 }
 
 macro_rules! binary_op {
@@ -21,24 +92,1038 @@ macro_rules! binary_op {
 }
 
 impl VM {
-    pub fn new(program: Vec<OpCode>) -> VM {
-        return VM{
-            program: program,
+    pub fn new() -> VM {
+        let mut heap = Heap::new();
+        let array_prototype = heap.alloc(Object::Ordinary(PropertyMap::new()));
+        let generator_prototype = heap.alloc(Object::Ordinary(PropertyMap::new()));
+        let promise_prototype = heap.alloc(Object::Ordinary(PropertyMap::new()));
+        let mut vm = VM{
+            frames: Vec::new(),
             stack: Vec::new(),
-            ip: 0,
-            running: false,
+            globals: HashMap::new(),
+            heap,
+            open_upvalues: Vec::new(),
+            array_prototype,
+            generator_prototype,
+            promise_prototype,
+            jobs: VecDeque::new(),
+            host_promises: Vec::new(),
+            unhandled: Vec::new(),
+            timers: Vec::new(),
+            timer_count: 0,
+            clock: Box::new(SystemClock::new()),
+            max_depth: DEFAULT_MAX_CALL_DEPTH,
+            executions: 0,
         };
+        array::install(&mut vm, array_prototype);
+        generator::install(&mut vm, generator_prototype);
+        promise::install(&mut vm, promise_prototype);
+        timer::install(&mut vm);
+        vm
+    }
+
+    /// Sets how many nested calls may be active before the VM raises a
+    /// "Maximum call stack size exceeded" error.
+    #[cfg(test)]
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Sets how many bytes may be live before the next garbage collection.
+    #[cfg(test)]
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.heap.set_threshold(bytes);
+    }
+
+    #[cfg(test)]
+    pub fn heap_stats(&mut self) -> HeapStats {
+        self.heap.stats()
     }
 
-    pub fn run(&mut self, scopes: &mut HashMap<String, Value>) -> Result<Option<Value>, String> {
-        self.running = true;
-        while self.running && self.ip < self.program.len() {
-            match self.program[self.ip] {
+    /// Moves an object onto the heap.
+    pub fn alloc(&mut self, object: Object) -> Value {
+        Value::Object(self.heap.alloc(object))
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// The names of every global currently defined.
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals.keys().cloned().collect()
+    }
+
+    /// Frees every object the running script can no longer reach.
+    pub fn collect_garbage(&mut self) {
+        let mut queued = Vec::new();
+        for job in &self.jobs {
+            job.reaction.trace(&mut queued);
+            queued.extend(job.value.handle());
+        }
+        for timer in &self.timers {
+            queued.extend(timer.callback.handle());
+            queued.extend(timer.args.iter().filter_map(Value::handle));
+        }
+        let roots = self.stack.iter().filter_map(Value::handle)
+            .chain(self.globals.values().filter_map(Value::handle))
+            .chain(self.frames.iter().map(|f| f.closure))
+            .chain(self.frames.iter().filter_map(|f| f.generator))
+            .chain(Some(self.array_prototype))
+            .chain(Some(self.generator_prototype))
+            .chain(Some(self.promise_prototype))
+            .chain(self.host_promises.iter().cloned())
+            .chain(self.unhandled.iter().cloned())
+            .chain(queued)
+            .chain(self.open_upvalues.iter().cloned());
+        self.heap.collect(roots);
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn jump(&mut self, offset: i32) {
+        let frame = self.frame();
+        frame.ip = (frame.ip as i32 + offset) as usize;
+    }
+
+    /// Calls the function below `args_len` arguments and a `this` value on
+    /// the stack. Script functions get a new frame; natives run right away.
+    fn call(&mut self, args_len: usize) -> Result<(), RuntimeError> {
+        let callee_slot = self.stack.len() - args_len - 2;
+        let callee = match self.stack[callee_slot] {
+            Value::Object(handle) => handle,
+            ref v => return Err(self.not_a_function(v)),
+        };
+        match *self.heap.get(callee) {
+            Object::Closure(ref closure) => {
+                if self.frames.len() >= self.max_depth {
+                    return Err(RuntimeError::range_error("Maximum call stack size exceeded"));
+                }
+                // Missing arguments are undefined and extra ones are dropped.
+                let function = closure.function.clone();
+                self.stack.resize(callee_slot + 2 + function.arity, Value::Undefined);
+                if function.kind != FunctionKind::Normal {
+                    // The body runs as a generator. An async function starts
+                    // it right away, while a generator waits to be resumed.
+                    let kind = function.kind;
+                    let stack = self.stack.split_off(callee_slot);
+                    let generator = self.heap.alloc(Object::Generator(Generator{
+                        closure: callee,
+                        function,
+                        ip: 0,
+                        stack,
+                        upvalues: Vec::new(),
+                        state: GeneratorState::Created,
+                    }));
+                    let result = match kind {
+                        FunctionKind::Async => {
+                            let promise = self.heap.alloc(Object::Promise(Promise::new(PromiseState::Pending)));
+                            self.step_async(generator, promise, Resume::Next, Value::Undefined);
+                            promise
+                        },
+                        _ => generator,
+                    };
+                    self.stack.push(Value::Object(result));
+                    return Ok(());
+                }
+                self.frames.push(Frame{
+                    closure: callee,
+                    function,
+                    ip: 0,
+                    base: callee_slot,
+                    generator: None,
+                });
+            },
+            Object::Native(Native::Function(f)) => {
+                // The arguments stay on the stack while the native runs so
+                // that they survive any collection it triggers.
+                let this = self.stack[callee_slot + 1].clone();
+                let arg_values = self.stack[callee_slot + 2..].to_vec();
+                let result = f(self, this, arg_values)?;
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
+            },
+            _ => return Err(self.not_a_function(&Value::Object(callee))),
+        }
+        Ok(())
+    }
+
+    fn not_a_function(&self, v: &Value) -> RuntimeError {
+        let type_name = self.type_of(v);
+        RuntimeError::TypeError{
+            message: format!("{} is not a function", type_name),
+            operands: vec!(type_name),
+        }
+    }
+
+    /// The type of a value as `typeof` reports it.
+    pub fn type_of(&self, v: &Value) -> &'static str {
+        match *v {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Closure(_) | Object::Native(_) => "function",
+                _ => "object",
+            },
+            ref v => v.type_name(),
+        }
+    }
+
+    /// Calls a function from native code, running it to completion.
+    pub fn call_function(&mut self, callee: Value, this: Value, args: Vec<Value>)
+            -> Result<Value, RuntimeError> {
+        let depth = self.frames.len();
+        let args_len = args.len();
+        self.stack.push(callee);
+        self.stack.push(this);
+        self.stack.extend(args);
+        self.call(args_len)?;
+        self.execute(depth)?;
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// Keeps a value alive across collections until it is popped again.
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    pub fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    /// Returns the upvalue for a stack slot, reusing an open one so that
+    /// every closure capturing the same variable shares it.
+    fn capture_upvalue(&mut self, slot: usize) -> Gc {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, &upvalue) in self.open_upvalues.iter().enumerate() {
+            match *self.heap.get(upvalue) {
+                Object::Upvalue(Upvalue::Open(s)) if s == slot => return upvalue,
+                Object::Upvalue(Upvalue::Open(s)) if s > slot => {
+                    insert_at = i;
+                    break;
+                },
+                _ => (),
+            }
+        }
+        let upvalue = self.heap.alloc(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Moves every captured variable at or above `slot` off the stack.
+    fn close_upvalues(&mut self, slot: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let object = self.heap.get_mut(upvalue);
+            let s = match *object {
+                Object::Upvalue(Upvalue::Open(s)) => s,
+                _ => unreachable!(),
+            };
+            if s < slot {
+                break;
+            }
+            *object = Object::Upvalue(Upvalue::Closed(self.stack[s].clone()));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn make_closure(&mut self, function: &Rc<Function>) -> Value {
+        let base = self.frame().base;
+        let enclosing = self.frame().closure;
+        let upvalues = function.captures.iter().map(|c| {
+            if c.is_local {
+                self.capture_upvalue(base + c.index)
+            } else {
+                match *self.heap.get(enclosing) {
+                    Object::Closure(ref e) => e.upvalues[c.index],
+                    _ => unreachable!(),
+                }
+            }
+        }).collect();
+        self.alloc(Object::Closure(Closure{function: function.clone(), upvalues}))
+    }
+
+    /// Pops the current frame, leaving its result on the caller's stack.
+    fn ret(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        let result = self.stack.pop().unwrap();
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+        self.stack.push(result);
+    }
+
+    fn upvalue_handle(&self, index: usize) -> Gc {
+        match *self.heap.get(self.frames.last().unwrap().closure) {
+            Object::Closure(ref c) => c.upvalues[index],
+            _ => unreachable!(),
+        }
+    }
+
+    fn upvalue(&self, index: usize) -> Value {
+        match *self.heap.get(self.upvalue_handle(index)) {
+            Object::Upvalue(Upvalue::Open(slot)) => self.stack[slot].clone(),
+            Object::Upvalue(Upvalue::Closed(ref v)) => v.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_upvalue(&mut self, index: usize, value: Value) {
+        let handle = self.upvalue_handle(index);
+        match *self.heap.get_mut(handle) {
+            Object::Upvalue(Upvalue::Open(slot)) => self.stack[slot] = value,
+            Object::Upvalue(Upvalue::Closed(ref mut v)) => *v = value,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_property(&self, object: &Value, key: &Value) -> Result<Value, RuntimeError> {
+        match *object {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Ordinary(ref props) => {
+                    let key = key.to_property_key();
+                    Ok(props.get(&key).cloned().unwrap_or(Value::Undefined))
+                },
+                Object::Array(ref elements) => {
+                    if let Some(i) = key.to_array_index() {
+                        return Ok(elements.get(i).cloned().unwrap_or(Value::Undefined));
+                    }
+                    if key.to_property_key() == "length" {
+                        return Ok(Value::Number(elements.len() as f64));
+                    }
+                    self.get_property(&Value::Object(self.array_prototype), key)
+                },
+                Object::Generator(_) => {
+                    self.get_property(&Value::Object(self.generator_prototype), key)
+                },
+                Object::Promise(_) => {
+                    self.get_property(&Value::Object(self.promise_prototype), key)
+                },
+                _ => Ok(Value::Undefined),
+            },
+            Value::Undefined => Err(RuntimeError::TypeError{
+                message: format!("cannot read property '{}' of undefined", key.to_property_key()),
+                operands: vec!("undefined"),
+            }),
+            _ => Ok(Value::Undefined),
+        }
+    }
+
+    pub fn set_property(&mut self, object: &Value, key: &Value, value: Value)
+            -> Result<(), RuntimeError> {
+        match *object {
+            Value::Object(handle) => match *self.heap.get_mut(handle) {
+                Object::Ordinary(ref mut props) => {
+                    props.set(key.to_property_key(), value);
+                    Ok(())
+                },
+                Object::Array(ref mut elements) => {
+                    if let Some(i) = key.to_array_index() {
+                        if i >= MAX_ARRAY_LENGTH {
+                            return Err(RuntimeError::range_error("invalid array length"));
+                        }
+                        if i >= elements.len() {
+                            elements.resize(i + 1, Value::Undefined);
+                        }
+                        elements[i] = value;
+                        return Ok(());
+                    }
+                    match (key.to_property_key().as_ref(), value) {
+                        ("length", Value::Number(n))
+                                if n >= 0.0 && n.fract() == 0.0 && n <= MAX_ARRAY_LENGTH as f64 => {
+                            elements.resize(n as usize, Value::Undefined);
+                            Ok(())
+                        },
+                        ("length", _) => Err(RuntimeError::range_error("invalid array length")),
+                        (key, _) => Err(RuntimeError::type_error(
+                            &format!("cannot set property '{}' of an array", key))),
+                    }
+                },
+                _ => Err(self.cannot_set(object, key)),
+            },
+            _ => Err(self.cannot_set(object, key)),
+        }
+    }
+
+    fn cannot_set(&self, object: &Value, key: &Value) -> RuntimeError {
+        let type_name = self.type_of(object);
+        RuntimeError::TypeError{
+            message: format!("cannot set property '{}' of {}", key.to_property_key(), type_name),
+            operands: vec!(type_name),
+        }
+    }
+
+    /// Converts a value to a string the way scripts see it, looking inside
+    /// heap objects where needed.
+    pub fn to_string(&self, value: &Value) -> String {
+        match *value {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Array(ref elements) => {
+                    let parts: Vec<String> = elements.iter().map(|e| match *e {
+                        Value::Undefined => String::new(),
+                        ref e => self.to_string(e),
+                    }).collect();
+                    parts.join(",")
+                },
+                Object::Closure(_) | Object::Native(_) => "function".to_string(),
+                _ => "[object Object]".to_string(),
+            },
+            ref v => format!("{}", v),
+        }
+    }
+
+    /// Gets an iterator for `for...of`. Arrays and strings are iterated by the
+    /// VM itself; other objects provide an `@@iterator` method returning an
+    /// object whose `next()` returns `{value, done}` results.
+    fn get_iterator(&mut self, iterable: Value) -> Result<Value, RuntimeError> {
+        let builtin = match iterable {
+            Value::Str(_) => true,
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Array(_) => true,
+                Object::Iterator(_) => return Ok(iterable),
+                _ => false,
+            },
+            _ => false,
+        };
+        if builtin {
+            return Ok(self.alloc(Object::Iterator(BuiltinIterator{source: iterable, next: 0})));
+        }
+        let key = Value::Str(ITERATOR_KEY.to_string());
+        let method = match iterable {
+            Value::Object(_) => self.get_property(&iterable, &key)?,
+            _ => Value::Undefined,
+        };
+        if method == Value::Undefined {
+            let type_name = self.type_of(&iterable);
+            return Err(RuntimeError::TypeError{
+                message: format!("{} is not iterable", type_name),
+                operands: vec!(type_name),
+            });
+        }
+        match self.call_function(method, iterable, Vec::new())? {
+            iterator @ Value::Object(_) => Ok(iterator),
+            _ => Err(RuntimeError::type_error("result of @@iterator is not an object")),
+        }
+    }
+
+    /// An iterator over the own keys of an object, for `for...in`.
+    fn get_keys(&mut self, object: &Value) -> Value {
+        let keys: Vec<Value> = match *object {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Ordinary(ref props) => {
+                    props.keys().iter().map(|k| Value::Str(k.clone())).collect()
+                },
+                Object::Array(ref elements) => {
+                    (0..elements.len()).map(|i| Value::Str(i.to_string())).collect()
+                },
+                _ => Vec::new(),
+            },
+            Value::Str(ref s) => (0..s.chars().count()).map(|i| Value::Str(i.to_string())).collect(),
+            _ => Vec::new(),
+        };
+        let keys = self.alloc(Object::Array(keys));
+        self.alloc(Object::Iterator(BuiltinIterator{source: keys, next: 0}))
+    }
+
+    /// Advances an iterator, returning `None` once it is done.
+    fn iterator_next(&mut self, iterator: &Value) -> Result<Option<Value>, RuntimeError> {
+        if let Value::Object(handle) = *iterator {
+            if let Object::Iterator(ref it) = *self.heap.get(handle) {
+                let (value, next) = match it.source {
+                    Value::Str(ref s) => match s[it.next..].chars().next() {
+                        Some(c) => (Value::Str(c.to_string()), it.next + c.len_utf8()),
+                        None => return Ok(None),
+                    },
+                    Value::Object(source) => match *self.heap.get(source) {
+                        Object::Array(ref elements) if it.next < elements.len() => {
+                            (elements[it.next].clone(), it.next + 1)
+                        },
+                        _ => return Ok(None),
+                    },
+                    _ => return Ok(None),
+                };
+                if let Object::Iterator(ref mut it) = *self.heap.get_mut(handle) {
+                    it.next = next;
+                }
+                return Ok(Some(value));
+            }
+        }
+        let next = self.get_property(iterator, &Value::Str("next".to_string()))?;
+        let result = self.call_function(next, iterator.clone(), Vec::new())?;
+        if self.get_property(&result, &Value::Str("done".to_string()))?.to_boolean() {
+            return Ok(None);
+        }
+        Ok(Some(self.get_property(&result, &Value::Str("value".to_string()))?))
+    }
+
+    /// Tells an iterator a loop stopped before it was done, by calling its
+    /// `return` method if it has one.
+    fn iterator_close(&mut self, iterator: &Value) -> Result<(), RuntimeError> {
+        if let Value::Object(handle) = *iterator {
+            if let Object::Iterator(_) = *self.heap.get(handle) {
+                return Ok(());
+            }
+        }
+        let method = self.get_property(iterator, &Value::Str("return".to_string()))?;
+        if method != Value::Undefined {
+            self.call_function(method, iterator.clone(), Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Converts a value to a number, going through its string form for
+    /// heap objects.
+    pub fn to_number(&self, value: &Value) -> f64 {
+        match *value {
+            Value::Object(_) => string_to_number(&self.to_string(value)),
+            ref v => v.to_number(),
+        }
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Resumes a generator and runs it until it yields or finishes, giving
+    /// the value it produced and whether it is done.
+    pub fn resume_generator(&mut self, generator: &Value, how: Resume, value: Value)
+            -> Result<(Value, bool), RuntimeError> {
+        let handle = match *generator {
+            Value::Object(handle) if matches!(*self.heap.get(handle), Object::Generator(_)) => handle,
+            ref v => {
+                let type_name = self.type_of(v);
+                return Err(RuntimeError::TypeError{
+                    message: format!("{} is not a generator", type_name),
+                    operands: vec!(type_name),
+                });
+            },
+        };
+        let state = match *self.heap.get(handle) {
+            Object::Generator(ref g) => g.state,
+            _ => unreachable!(),
+        };
+        match (state, how) {
+            (GeneratorState::Running, _) => {
+                return Err(RuntimeError::type_error("generator is already running"));
+            },
+            (GeneratorState::Created, Resume::Next) | (GeneratorState::Suspended, _) => (),
+            (_, how) => {
+                // A generator that never started finishes without running.
+                self.finish_generator(handle);
+                return match how {
+                    Resume::Next => Ok((Value::Undefined, true)),
+                    Resume::Return => Ok((value, true)),
+                    Resume::Throw => Err(self.thrown(value)),
+                };
+            },
+        }
+
+        // Put the frame back on top of the stack.
+        let depth = self.frames.len();
+        let base = self.stack.len();
+        let (closure, function, ip, stack, upvalues) = match *self.heap.get_mut(handle) {
+            Object::Generator(ref mut g) => {
+                g.state = GeneratorState::Running;
+                (g.closure, g.function.clone(), g.ip, mem::take(&mut g.stack),
+                 mem::take(&mut g.upvalues))
+            },
+            _ => unreachable!(),
+        };
+        self.stack.extend(stack);
+        for (upvalue, slot) in upvalues {
+            // The variable may have been changed through the upvalue while
+            // the generator was suspended.
+            let object = self.heap.get_mut(upvalue);
+            if let Object::Upvalue(Upvalue::Closed(ref v)) = *object {
+                self.stack[base + slot] = v.clone();
+            }
+            *object = Object::Upvalue(Upvalue::Open(base + slot));
+            self.open_upvalues.push(upvalue);
+        }
+        // A suspended generator stopped just after a `Yield`, which says
+        // where its code for returning from there is, and how much of the
+        // stack is locals rather than operands of the suspended expression.
+        let ip = match (how, ip.checked_sub(1).map(|at| &function.body[at])) {
+            (Resume::Return, Some(&OpCode::Yield(n, locals))) => {
+                self.stack.truncate(base + locals);
+                (ip as i32 + n - 1) as usize
+            },
+            _ => ip,
+        };
+        self.frames.push(Frame{closure, function, ip, base, generator: Some(handle)});
+
+        let result = match (state, how) {
+            (GeneratorState::Created, _) => self.execute(depth),
+            (_, Resume::Next) | (_, Resume::Return) => {
+                self.stack.push(value);
+                self.execute(depth)
+            },
+            (_, Resume::Throw) => {
+                let error = self.thrown(value);
+                self.unwind(error, depth).and_then(|()| self.execute(depth))
+            },
+        };
+        if let Err(error) = result {
+            self.finish_generator(handle);
+            return Err(error);
+        }
+        let value = self.stack.pop().unwrap();
+        match *self.heap.get(handle) {
+            Object::Generator(ref g) if g.state == GeneratorState::Suspended => Ok((value, false)),
+            _ => {
+                self.finish_generator(handle);
+                Ok((value, true))
+            },
+        }
+    }
+
+    fn finish_generator(&mut self, handle: Gc) {
+        if let Object::Generator(ref mut g) = *self.heap.get_mut(handle) {
+            g.state = GeneratorState::Done;
+            g.stack = Vec::new();
+            g.upvalues = Vec::new();
+        }
+    }
+
+    /// Moves the innermost frame, which belongs to a generator, off the
+    /// stack and into the generator.
+    fn suspend_generator(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        let handle = frame.generator.expect("the parser only allows yield in generators");
+        // Variables captured from the frame are closed while it is away.
+        let first = self.open_upvalues.iter().position(|&upvalue| match *self.heap.get(upvalue) {
+            Object::Upvalue(Upvalue::Open(slot)) => slot >= frame.base,
+            _ => unreachable!(),
+        }).unwrap_or(self.open_upvalues.len());
+        let captured: Vec<Gc> = self.open_upvalues.drain(first..).collect();
+        let upvalues = captured.into_iter().map(|upvalue| {
+            let object = self.heap.get_mut(upvalue);
+            let slot = match *object {
+                Object::Upvalue(Upvalue::Open(slot)) => slot,
+                _ => unreachable!(),
+            };
+            *object = Object::Upvalue(Upvalue::Closed(self.stack[slot].clone()));
+            (upvalue, slot - frame.base)
+        }).collect();
+        let stack = self.stack.split_off(frame.base);
+        if let Object::Generator(ref mut g) = *self.heap.get_mut(handle) {
+            g.ip = frame.ip;
+            g.stack = stack;
+            g.upvalues = upvalues;
+            g.state = GeneratorState::Suspended;
+        }
+    }
+
+    /// Makes a pending promise for the host to settle later with
+    /// `resolve_promise` or `reject_promise`. It stays alive until then.
+    #[cfg(test)]
+    pub fn new_promise(&mut self) -> Value {
+        let promise = self.heap.alloc(Object::Promise(Promise::new(PromiseState::Pending)));
+        self.host_promises.push(promise);
+        Value::Object(promise)
+    }
+
+    /// Resolves a promise: with another promise it follows that one,
+    /// otherwise it is fulfilled with the value. Settled promises are left
+    /// as they are.
+    #[cfg(test)]
+    pub fn resolve_promise(&mut self, promise: &Value, value: Value) -> Result<(), RuntimeError> {
+        let handle = self.promise_handle(promise)?;
+        self.host_promises.retain(|&p| p != handle);
+        self.resolve(handle, value);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn reject_promise(&mut self, promise: &Value, reason: Value) -> Result<(), RuntimeError> {
+        let handle = self.promise_handle(promise)?;
+        self.host_promises.retain(|&p| p != handle);
+        self.settle(handle, PromiseState::Rejected(reason));
+        Ok(())
+    }
+
+    /// The state of a promise, or `None` if the value isn't one.
+    pub fn promise_state(&self, promise: &Value) -> Option<PromiseState> {
+        match *promise {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Promise(ref p) => Some(p.state.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Registers callbacks for when a promise settles, returning a promise
+    /// of what they return. Anything but a function is ignored.
+    pub fn promise_then(&mut self, promise: &Value, on_fulfilled: Value, on_rejected: Value)
+            -> Result<Value, RuntimeError> {
+        let handle = self.promise_handle(promise)?;
+        let callback = |vm: &VM, v: Value| if vm.type_of(&v) == "function" { v } else { Value::Undefined };
+        let on_fulfilled = callback(self, on_fulfilled);
+        let on_rejected = callback(self, on_rejected);
+        let derived = self.heap.alloc(Object::Promise(Promise::new(PromiseState::Pending)));
+        self.add_reaction(handle, Reaction::Then{on_fulfilled, on_rejected, derived});
+        Ok(Value::Object(derived))
+    }
+
+    /// A promise of a value: the value itself if it is a promise already.
+    pub fn promise_for(&mut self, value: Value) -> Value {
+        if self.promise_state(&value).is_some() {
+            return value;
+        }
+        self.alloc(Object::Promise(Promise::new(PromiseState::Fulfilled(value))))
+    }
+
+    /// The first rejection nothing has reacted to, as the error it would be
+    /// if it escaped the script. The rest are forgotten. Hosts check once
+    /// there is nothing left to run, as a later `catch` still handles one.
+    pub fn take_unhandled_rejection(&mut self) -> Option<ScriptError> {
+        let promise = *self.unhandled.first()?;
+        self.unhandled.clear();
+        let reason = match *self.heap.get(promise) {
+            Object::Promise(Promise{state: PromiseState::Rejected(ref reason), ..}) => reason.clone(),
+            _ => unreachable!(),
+        };
+        Some(ScriptError{error: self.thrown(reason), trace: Vec::new()})
+    }
+
+    /// Runs promise reactions until there are none left, including any
+    /// queued while doing so. Errors in the reactions reject promises
+    /// rather than escaping.
+    pub fn run_jobs(&mut self) {
+        while let Some(job) = self.jobs.pop_front() {
+            self.run_job(job);
+        }
+    }
+
+    fn promise_handle(&self, promise: &Value) -> Result<Gc, RuntimeError> {
+        match *promise {
+            Value::Object(handle) if matches!(*self.heap.get(handle), Object::Promise(_)) => Ok(handle),
+            ref v => {
+                let type_name = self.type_of(v);
+                Err(RuntimeError::TypeError{
+                    message: format!("{} is not a promise", type_name),
+                    operands: vec!(type_name),
+                })
+            },
+        }
+    }
+
+    fn resolve(&mut self, promise: Gc, value: Value) {
+        match value {
+            Value::Object(handle) if handle == promise => {
+                let error = RuntimeError::type_error("a promise cannot be resolved with itself");
+                let reason = self.error_value(error);
+                self.settle(promise, PromiseState::Rejected(reason));
+            },
+            Value::Object(handle) if matches!(*self.heap.get(handle), Object::Promise(_)) => {
+                self.add_reaction(handle, Reaction::Then{
+                    on_fulfilled: Value::Undefined,
+                    on_rejected: Value::Undefined,
+                    derived: promise,
+                });
+            },
+            value => self.settle(promise, PromiseState::Fulfilled(value)),
+        }
+    }
+
+    /// Fulfills or rejects a pending promise, queueing its reactions.
+    fn settle(&mut self, promise: Gc, state: PromiseState) {
+        let reactions = match *self.heap.get_mut(promise) {
+            Object::Promise(ref mut p) if p.state == PromiseState::Pending => {
+                p.state = state.clone();
+                mem::take(&mut p.reactions)
+            },
+            _ => return,
+        };
+        if reactions.is_empty() && matches!(state, PromiseState::Rejected(_)) {
+            self.unhandled.push(promise);
+        }
+        for reaction in reactions {
+            self.queue_job(reaction, &state);
+        }
+    }
+
+    fn add_reaction(&mut self, promise: Gc, reaction: Reaction) {
+        self.unhandled.retain(|&p| p != promise);
+        let state = match *self.heap.get_mut(promise) {
+            Object::Promise(ref mut p) if p.state == PromiseState::Pending => {
+                p.reactions.push(reaction);
+                return;
+            },
+            Object::Promise(ref p) => p.state.clone(),
+            _ => unreachable!(),
+        };
+        self.queue_job(reaction, &state);
+    }
+
+    fn queue_job(&mut self, reaction: Reaction, state: &PromiseState) {
+        let (value, rejected) = match *state {
+            PromiseState::Fulfilled(ref v) => (v.clone(), false),
+            PromiseState::Rejected(ref v) => (v.clone(), true),
+            PromiseState::Pending => unreachable!(),
+        };
+        self.jobs.push_back(Job{reaction, value, rejected});
+    }
+
+    fn run_job(&mut self, job: Job) {
+        match job.reaction {
+            Reaction::Then{on_fulfilled, on_rejected, derived} => {
+                let callback = if job.rejected { on_rejected } else { on_fulfilled };
+                let outcome = match callback {
+                    Value::Undefined if job.rejected => Err(job.value),
+                    Value::Undefined => Ok(job.value),
+                    callback => {
+                        // Nothing else refers to the derived promise yet.
+                        self.stack.push(Value::Object(derived));
+                        let args = vec!(job.value);
+                        let outcome = self.catching(|vm| vm.call_function(callback, Value::Undefined, args));
+                        self.stack.pop();
+                        outcome
+                    },
+                };
+                match outcome {
+                    Ok(value) => self.resolve(derived, value),
+                    Err(reason) => self.settle(derived, PromiseState::Rejected(reason)),
+                }
+            },
+            Reaction::Await{generator, promise} => {
+                let how = if job.rejected { Resume::Throw } else { Resume::Next };
+                self.step_async(generator, promise, how, job.value);
+            },
+        }
+    }
+
+    /// Runs an async function's body until it awaits or finishes, settling
+    /// its promise once it does finish.
+    fn step_async(&mut self, generator: Gc, promise: Gc, how: Resume, value: Value) {
+        self.stack.push(Value::Object(promise));
+        let outcome = self.catching(|vm| vm.resume_generator(&Value::Object(generator), how, value));
+        self.stack.pop();
+        match outcome {
+            Ok((value, true)) => self.resolve(promise, value),
+            Ok((value, false)) => {
+                let awaited = match self.promise_for(value) {
+                    Value::Object(handle) => handle,
+                    _ => unreachable!(),
+                };
+                self.add_reaction(awaited, Reaction::Await{generator, promise});
+            },
+            Err(reason) => self.settle(promise, PromiseState::Rejected(reason)),
+        }
+    }
+
+    /// Runs `f`, turning any error it raises into the value a script would
+    /// catch and dropping the frames it left behind.
+    fn catching<T, F>(&mut self, f: F) -> Result<T, Value>
+            where F: FnOnce(&mut VM) -> Result<T, RuntimeError> {
+        let depth = self.frames.len();
+        let height = self.stack.len();
+        f(self).map_err(|error| {
+            self.close_upvalues(height);
+            self.frames.truncate(depth);
+            self.stack.truncate(height);
+            self.error_value(error)
+        })
+    }
+
+    /// Replaces the clock timers are measured against.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn clock_mut(&mut self) -> &mut dyn Clock {
+        &mut *self.clock
+    }
+
+    /// Schedules `callback` to be called with `args` once `delay`
+    /// milliseconds have passed, and then every `interval` milliseconds if
+    /// given. Returns the timer's id.
+    pub fn add_timer(&mut self, callback: Value, delay: u64, interval: Option<u64>, args: Vec<Value>)
+            -> u64 {
+        self.timer_count += 1;
+        let id = self.timer_count;
+        let deadline = self.clock.now().saturating_add(delay);
+        self.timers.push(Timer{id, deadline, seq: id, interval, callback, args});
+        id
+    }
+
+    pub fn clear_timer(&mut self, id: u64) {
+        self.timers.retain(|t| t.id != id);
+    }
+
+    /// When the next timer is due, if any are waiting.
+    pub fn next_timer(&self) -> Option<u64> {
+        self.timers.iter().map(|t| t.deadline).min()
+    }
+
+    /// Runs every timer that is due, earliest first, along with the promise
+    /// jobs each one queues. Timers scheduled meanwhile wait for the next
+    /// call, even if they are already due.
+    pub fn run_due_timers(&mut self) -> Result<(), ScriptError> {
+        let now = self.clock.now();
+        let last = self.timer_count;
+        loop {
+            let due = self.timers.iter().enumerate()
+                .filter(|&(_, t)| t.deadline <= now && t.seq <= last)
+                .min_by_key(|&(_, t)| (t.deadline, t.seq))
+                .map(|(i, _)| i);
+            let mut timer = match due {
+                Some(i) => self.timers.remove(i),
+                None => return Ok(()),
+            };
+            let (callback, args) = (timer.callback.clone(), timer.args.clone());
+            if let Some(interval) = timer.interval {
+                self.timer_count += 1;
+                timer.deadline = now.saturating_add(interval);
+                timer.seq = self.timer_count;
+                self.timers.push(timer);
+            }
+            let depth = self.frames.len();
+            let height = self.stack.len();
+            if let Err(error) = self.call_function(callback, Value::Undefined, args) {
+                let trace = self.stack_trace();
+                self.close_upvalues(height);
+                self.frames.truncate(depth);
+                self.stack.truncate(height);
+                return Err(ScriptError{error, trace});
+            }
+            self.stack.truncate(height);
+            self.run_jobs();
+        }
+    }
+
+    /// Runs a compiled script to completion. Globals and heap objects
+    /// survive from one run to the next.
+    pub fn run(&mut self, script: Function) -> Result<Option<Value>, ScriptError> {
+        let script = Rc::new(script);
+        let closure = self.heap.alloc(Object::Closure(Closure{
+            function: script.clone(),
+            upvalues: Vec::new(),
+        }));
+        // Anything captured by a previous run that never finished keeps the
+        // value it had when that run stopped.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.frames.push(Frame{closure, function: script, ip: 0, base: 0, generator: None});
+        if let Err(error) = self.execute(0) {
+            return Err(ScriptError{error, trace: self.stack_trace()});
+        }
+        match self.stack.last() {
+            Some(result) => Ok(Some(result.clone())),
+            None => Ok(None)
+        }
+    }
+
+    /// Describes the active calls, innermost first.
+    fn stack_trace(&self) -> Vec<StackFrame> {
+        self.frames.iter().rev().map(|frame| {
+            let function = &frame.function;
+            // The frame's ip has already moved past the instruction that
+            // failed or made the call.
+            let span = function.lines.get(frame.ip.wrapping_sub(1));
+            let location = span.map(|span| Location{
+                file: (*function.file).clone(),
+                line: span.line,
+                col: span.col,
+            });
+            StackFrame{function: function.name.clone(), location, span: span.cloned()}
+        }).collect()
+    }
+
+    /// Runs the dispatch loop until the call stack is back to `depth` frames.
+    /// Errors are handled by the innermost handler in the frames above
+    /// `depth`; anything else is returned for the caller to handle.
+    fn execute(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        if self.executions > MAX_NATIVE_REENTRY {
+            return Err(RuntimeError::range_error("Maximum call stack size exceeded"));
+        }
+        self.executions += 1;
+        let result = loop {
+            match self.dispatch(depth) {
+                Ok(()) => break Ok(()),
+                Err(error) => if let Err(error) = self.unwind(error, depth) {
+                    break Err(error);
+                },
+            }
+        };
+        self.executions -= 1;
+        result
+    }
+
+    /// Pops frames down to the nearest handler for an error raised by the
+    /// innermost frame and resumes there with the error value on the stack.
+    fn unwind(&mut self, error: RuntimeError, depth: usize) -> Result<(), RuntimeError> {
+        for i in (depth..self.frames.len()).rev() {
+            let (handler, base) = {
+                let frame = &self.frames[i];
+                // Each frame stopped just after the instruction that failed
+                // or made the call that failed.
+                let at = frame.ip.wrapping_sub(1);
+                match frame.function.handlers.iter().find(|h| h.start <= at && at < h.end) {
+                    Some(handler) => (handler.clone(), frame.base),
+                    None => continue,
+                }
+            };
+            let value = self.error_value(error);
+            self.frames.truncate(i + 1);
+            self.close_upvalues(base + handler.depth);
+            self.stack.truncate(base + handler.depth);
+            self.stack.push(value);
+            self.frames[i].ip = handler.target;
+            return Ok(());
+        }
+        Err(error)
+    }
+
+    /// The value a script catches for an error.
+    fn error_value(&mut self, error: RuntimeError) -> Value {
+        if let RuntimeError::Thrown{value, ..} = error {
+            return value;
+        }
+        let mut props = PropertyMap::new();
+        props.set("name".to_string(), Value::Str(error.name().to_string()));
+        props.set("message".to_string(), Value::Str(error.message()));
+        self.alloc(Object::Ordinary(props))
+    }
+
+    /// Wraps a value thrown by the script as an error, describing it the
+    /// way it would be reported if nothing catches it.
+    fn thrown(&self, value: Value) -> RuntimeError {
+        let description = match value {
+            Value::Object(handle) => match *self.heap.get(handle) {
+                Object::Ordinary(ref props) => match (props.get("name"), props.get("message")) {
+                    (Some(name), Some(message)) => {
+                        format!("{}: {}", self.to_string(name), self.to_string(message))
+                    },
+                    _ => self.to_string(&value),
+                },
+                _ => self.to_string(&value),
+            },
+            _ => self.to_string(&value),
+        };
+        RuntimeError::Thrown{value, description}
+    }
+
+    fn dispatch(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        while self.frames.len() > depth {
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            let function = self.frame().function.clone();
+            let ip = self.frame().ip;
+            if ip >= function.body.len() {
+                // Only the script itself can run off the end of its code.
+                self.frames.pop();
+                continue;
+            }
+            self.frame().ip += 1;
+            match function.body[ip] {
                 OpCode::Val(ref v)  => self.stack.push(v.clone()),
-                OpCode::Add         => binary_op!(self, a, b, try!(a.add(b))),
-                OpCode::Sub         => binary_op!(self, a, b, try!(a.sub(b))),
-                OpCode::Mul         => binary_op!(self, a, b, try!(a.mul(b))),
-                OpCode::Div         => binary_op!(self, a, b, try!(a.div(b))),
+                OpCode::Add         => binary_op!(self, a, b, a.add(b)?),
+                OpCode::Sub         => binary_op!(self, a, b, a.sub(b)?),
+                OpCode::Mul         => binary_op!(self, a, b, a.mul(b)?),
+                OpCode::Div         => binary_op!(self, a, b, a.div(b)?),
                 OpCode::EqEq        => binary_op!(self, a, b, Value::Bool(a == b)),
                 OpCode::NotEq       => binary_op!(self, a, b, Value::Bool(a != b)),
                 OpCode::Lt          => binary_op!(self, a, b, Value::Bool(a < b)),
@@ -48,87 +1133,181 @@ impl VM {
                 OpCode::Def         => {
                     match self.stack.pop().unwrap() {
                         Value::Str(s) => {
-                            scopes.insert(s, self.stack.pop().unwrap());
+                            let v = self.stack.pop().unwrap();
+                            self.globals.insert(s, v);
                         },
-                        _ => return Err("invalid assignment".to_string()),
+                        _ => panic!("bad bytecode"),
                     }
                 },
                 OpCode::GetName(ref n)  => {
-                    match scopes.get(n) {
+                    match self.globals.get(n) {
                         Some(v) => self.stack.push(v.clone()),
-                        None => ()
+                        None => return Err(RuntimeError::ReferenceError{name: n.clone()}),
                     }
                 },
-                OpCode::JumpIfNot(ref n) => {
+                OpCode::Neg => {
+                    let v = self.stack.pop().unwrap();
+                    let n = self.to_number(&v);
+                    self.stack.push(Value::Number(-n));
+                },
+                OpCode::Plus => {
+                    let v = self.stack.pop().unwrap();
+                    let n = self.to_number(&v);
+                    self.stack.push(Value::Number(n));
+                },
+                OpCode::Not => {
+                    let v = self.stack.pop().unwrap();
+                    self.stack.push(Value::Bool(!v.to_boolean()));
+                },
+                OpCode::Void => {
+                    self.stack.pop();
+                    self.stack.push(Value::Undefined);
+                },
+                OpCode::ToStr => {
+                    let v = self.stack.pop().unwrap();
+                    let s = self.to_string(&v);
+                    self.stack.push(Value::Str(s));
+                },
+                OpCode::TypeOf => {
+                    let v = self.stack.pop().unwrap();
+                    let type_name = self.type_of(&v);
+                    self.stack.push(Value::Str(type_name.to_string()));
+                },
+                OpCode::Throw => {
+                    let value = self.stack.pop().unwrap();
+                    return Err(self.thrown(value));
+                },
+                OpCode::Yield(..) => {
+                    let value = self.stack.pop().unwrap();
+                    self.suspend_generator();
+                    self.stack.push(value);
+                },
+                OpCode::TypeOfName(ref n) => {
+                    let type_name = match self.globals.get(n) {
+                        Some(v) => self.type_of(v),
+                        None => "undefined",
+                    };
+                    self.stack.push(Value::Str(type_name.to_string()));
+                },
+                OpCode::JumpIfNot(n) => {
                     if !self.stack.pop().unwrap().to_boolean() {
-                        self.ip = (self.ip as i32 + *n) as usize;
-                        continue;
+                        self.jump(n - 1);
                     }
                 },
-                OpCode::JumpIf(ref n) => {
+                OpCode::JumpIf(n) => {
                     if self.stack.pop().unwrap().to_boolean() {
-                        self.ip = (self.ip as i32 + *n) as usize;
-                        continue;
+                        self.jump(n - 1);
                     }
                 },
-                OpCode::Jump(ref n) => {
-                    self.ip = (self.ip as i32 + *n) as usize;
-                    continue;
-                },
-                OpCode::Ret => {
-                    self.running = false;
-                },
-                OpCode::Call => {
-                    let mut args_len =
-                        match self.stack.pop().unwrap() {
-                            Value::Number(n) => n as usize,
-                            _ => panic!("bad bytecode")
-                        };
-                    let mut arg_values = Vec::new();
-                    for _ in 0 .. args_len {
-                        arg_values.push(self.stack.pop().unwrap());
+                OpCode::JumpIfDefined(n) => {
+                    if !self.stack.pop().unwrap().is_nullish() {
+                        self.jump(n - 1);
                     }
-                    match self.stack.pop().unwrap() {
-                        Value::Object(o) => {
-                            match o {
-                                Object::Function{args, body} => {
-                                    let mut frame = VM::new(body);
-                                    for arg in args {
-                                        if args_len > 0 {
-                                            scopes.insert(arg.clone(), arg_values.pop().unwrap());
-                                            args_len -= 1;
-                                        } else {
-                                            scopes.insert(arg.clone(), Value::Undefined);
-                                        }
-                                    }
-                                    match try!(frame.run(scopes)) {
-                                        Some(result) => self.stack.push(result),
-                                        None => ()
-                                    }
-                                },
-                                Object::Native(nobj) => {
-                                   match nobj {
-                                      Native::Function(f) => {
-                                          // we won't be popping off the vector
-                                          // so let's flip it as a convenience
-                                          // (values will be in the true arg order)
-                                          arg_values.reverse();
-                                          self.stack.push(f(arg_values));
-                                      }
-                                   }
-                                }
-                            }
-                        },
-                        _ => return Err("invalid call".to_string()),
+                },
+                OpCode::Jump(n) => self.jump(n - 1),
+                OpCode::Ret => self.ret(),
+                OpCode::Call(args_len) => self.call(args_len)?,
+                OpCode::GetLocal(slot) => {
+                    let slot = self.frame().base + slot;
+                    let v = self.stack[slot].clone();
+                    self.stack.push(v);
+                },
+                OpCode::SetLocal(slot) => {
+                    let slot = self.frame().base + slot;
+                    self.stack[slot] = self.stack.pop().unwrap();
+                },
+                OpCode::GetUpvalue(index) => {
+                    let v = self.upvalue(index);
+                    self.stack.push(v);
+                },
+                OpCode::SetUpvalue(index) => {
+                    let v = self.stack.pop().unwrap();
+                    self.set_upvalue(index, v);
+                },
+                OpCode::CloseUpvalue => {
+                    let top = self.stack.len() - 1;
+                    self.close_upvalues(top);
+                    self.stack.pop();
+                },
+                OpCode::Closure(ref f) => {
+                    let closure = self.make_closure(f);
+                    self.stack.push(closure);
+                },
+                OpCode::Pop => { self.stack.pop(); },
+                OpCode::Dup => {
+                    let v = self.stack.last().unwrap().clone();
+                    self.stack.push(v);
+                },
+                OpCode::NewObject(len) => {
+                    let start = self.stack.len() - len * 2;
+                    let mut props = PropertyMap::new();
+                    let mut entries = self.stack.drain(start..);
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        props.set(key.to_property_key(), value);
+                    }
+                    drop(entries);
+                    let object = self.alloc(Object::Ordinary(props));
+                    self.stack.push(object);
+                },
+                OpCode::GetProp => {
+                    let key = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    let value = self.get_property(&object, &key)?;
+                    self.stack.push(value);
+                },
+                OpCode::SetProp => {
+                    let value = self.stack.pop().unwrap();
+                    let key = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    self.set_property(&object, &key, value.clone())?;
+                    self.stack.push(value);
+                },
+                OpCode::NewArray(len) => {
+                    let start = self.stack.len() - len;
+                    let elements = self.stack.split_off(start);
+                    let array = self.alloc(Object::Array(elements));
+                    self.stack.push(array);
+                },
+                OpCode::GetIterator => {
+                    let iterable = self.stack.pop().unwrap();
+                    // Keep the iterable rooted while a script @@iterator runs.
+                    self.stack.push(iterable.clone());
+                    let iterator = self.get_iterator(iterable)?;
+                    self.stack.pop();
+                    self.stack.push(iterator);
+                },
+                OpCode::GetKeys => {
+                    let object = self.stack.pop().unwrap();
+                    self.stack.push(object.clone());
+                    let keys = self.get_keys(&object);
+                    self.stack.pop();
+                    self.stack.push(keys);
+                },
+                OpCode::IterNext(n) => {
+                    let iterator = self.stack.last().unwrap().clone();
+                    match self.iterator_next(&iterator)? {
+                        Some(value) => self.stack.push(value),
+                        None => self.jump(n - 1),
                     }
                 },
+                OpCode::IterClose => {
+                    let iterator = self.stack.pop().unwrap();
+                    // Keep the iterator rooted while its `return` runs.
+                    self.stack.push(iterator.clone());
+                    self.iterator_close(&iterator)?;
+                    self.stack.pop();
+                },
+                OpCode::CallMethod(args_len) => {
+                    // [object, key, args...] becomes [method, object, args...]
+                    let object_slot = self.stack.len() - args_len - 2;
+                    let object = self.stack[object_slot].clone();
+                    let method = self.get_property(&object, &self.stack[object_slot + 1])?;
+                    self.stack[object_slot] = method;
+                    self.stack[object_slot + 1] = object;
+                    self.call(args_len)?;
+                },
             }
-            self.ip += 1;
-        }
-        self.running = false;
-        match self.stack.last() {
-            Some(result) => return Ok(Some(result.clone())),
-            None => return Ok(None)
         }
+        Ok(())
     }
 }